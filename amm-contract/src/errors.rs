@@ -0,0 +1,48 @@
+//! Errors of the pool's validation helpers, so the checks can be composed and tested as values.
+//! The entry points still panic, with the message of the error.
+
+use near_sdk::require;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PoolError {
+    /// the reserves or the token decimals are not known yet
+    NotInitialized,
+    /// a reserve is 0, so nothing can be priced until the pool is seeded again
+    EmptyPool,
+    SlippageExceeded,
+    InsufficientLiquidity,
+    Paused,
+    ZeroAmount,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PoolError::NotInitialized => "pool not initialized",
+            PoolError::EmptyPool => "pool is empty",
+            PoolError::SlippageExceeded => "slippage exceeded",
+            PoolError::InsufficientLiquidity => "insufficient liquidity for this trade",
+            PoolError::Paused => "contract is paused",
+            PoolError::ZeroAmount => "amount must be positive",
+        })
+    }
+}
+
+pub(crate) trait OrPanic<T> {
+    /// Unwraps the result, or panics with the message of the error as `require!` does.
+    fn or_panic(self) -> T;
+}
+
+impl<T> OrPanic<T> for Result<T, PoolError> {
+    fn or_panic(self) -> T {
+        match self {
+            Ok(value) => value,
+            Err(err) => {
+                // env::panic_str, but as a plain panic in unit tests, which can't unwind out of the mocked host
+                require!(false, err.to_string());
+                unreachable!()
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Events of the AMM contract, following the events format (nep-297):
+//! <https://github.com/near/NEPs/blob/master/specs/Standards/EventsFormat.md>
+//!
+//! They are logged as `EVENT_JSON:{"standard":"amm","version":"1.0.0","event":..,"data":[..]}`
+//! by calling `.emit()` on them.
+
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::{env, serde_json, AccountId};
+
+const STANDARD: &str = "amm";
+const VERSION: &str = "1.0.0";
+
+/// Data to log for a completed swap.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Swap<'a> {
+    pub account_id: &'a AccountId,
+    pub token_in: &'a AccountId,
+    pub amount_in: &'a U128,
+    pub token_out: &'a AccountId,
+    pub amount_out: &'a U128,
+}
+
+impl Swap<'_> {
+    pub fn emit(self) {
+        AmmEventKind::Swap(&[self]).emit()
+    }
+}
+
+/// Data to log for the reserves, whenever they change.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Sync {
+    pub a_ticker: U128,
+    pub b_ticker: U128,
+}
+
+impl Sync {
+    pub fn emit(self) {
+        AmmEventKind::Sync(&[self]).emit()
+    }
+}
+
+/// Data to log when a ticker update moves a reserve by more than the breaker allows,
+/// with the reserves before and the rejected ones.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CircuitBreakerTripped {
+    pub a_ticker: U128,
+    pub b_ticker: U128,
+    pub a_ticker_after: U128,
+    pub b_ticker_after: U128,
+}
+
+impl CircuitBreakerTripped {
+    pub fn emit(self) {
+        AmmEventKind::CircuitBreakerTripped(&[self]).emit()
+    }
+}
+
+/// Data to log when the owner points the protocol fees to another account.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeRecipientChanged<'a> {
+    pub old_recipient: &'a AccountId,
+    pub new_recipient: &'a AccountId,
+}
+
+impl FeeRecipientChanged<'_> {
+    pub fn emit(self) {
+        AmmEventKind::FeeRecipientChanged(&[self]).emit()
+    }
+}
+
+/// Data to log when the pool is initialized, with its starting reserves in minimum units.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolCreated<'a> {
+    pub owner_id: &'a AccountId,
+    pub a_contract_id: &'a AccountId,
+    pub b_contract_id: &'a AccountId,
+    pub a_initial: U128,
+    pub b_initial: U128,
+}
+
+impl PoolCreated<'_> {
+    pub fn emit(self) {
+        AmmEventKind::PoolCreated(&[self]).emit()
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data")]
+#[serde(rename_all = "snake_case")]
+enum AmmEventKind<'a> {
+    Swap(&'a [Swap<'a>]),
+    Sync(&'a [Sync]),
+    CircuitBreakerTripped(&'a [CircuitBreakerTripped]),
+    FeeRecipientChanged(&'a [FeeRecipientChanged<'a>]),
+    PoolCreated(&'a [PoolCreated<'a>]),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+struct AmmEvent<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event_kind: AmmEventKind<'a>,
+}
+
+impl AmmEventKind<'_> {
+    fn emit(self) {
+        let event = AmmEvent {
+            standard: STANDARD,
+            version: VERSION,
+            event_kind: self,
+        };
+        // events cannot fail to serialize
+        let json = serde_json::to_string(&event).unwrap_or_else(|_| env::abort());
+        env::log_str(&format!("EVENT_JSON:{}", json));
+    }
+}
@@ -1,45 +1,509 @@
+// the callbacks carry the whole swap state, which the generated ext methods cannot allow individually
+#![allow(clippy::too_many_arguments)]
+
+use errors::{OrPanic, PoolError};
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::{env, ext_contract, log, near_bindgen, require, AccountId, Balance, PanicOnDefault};
+use near_sdk::collections::{LookupMap, LookupSet, Vector};
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, log, near_bindgen, require, AccountId, Balance,
+    BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseError, PromiseOrValue, PromiseResult,
+    StorageUsage,
+};
+use u256::U256;
+
+mod errors;
+mod events;
+mod migration;
+mod storage;
+
+mod u256 {
+    #![allow(clippy::all)]
+    use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+
+    uint::construct_uint! {
+        /// 256-bit unsigned integer for intermediate products of reserves
+        pub struct U256(4);
+    }
+
+    impl BorshSerialize for U256 {
+        fn serialize<W: borsh::maybestd::io::Write>(
+            &self,
+            writer: &mut W,
+        ) -> borsh::maybestd::io::Result<()> {
+            self.0.serialize(writer)
+        }
+    }
 
-const A_TICKER: u128 = 40000000000000000000000;
-const B_TICKER: u128 = 300000000000000000000;
+    impl BorshDeserialize for U256 {
+        fn deserialize(buf: &mut &[u8]) -> borsh::maybestd::io::Result<Self> {
+            Ok(U256(<[u64; 4]>::deserialize(buf)?))
+        }
+    }
+}
+
+/// how long a proposed owner has to accept the ownership, 7 days in nanoseconds
+const OWNER_PROPOSAL_DURATION: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+/// the version of this contract, bumped along with the state migrations
+const VERSION: &str = "0.2.0";
+/// 0.30% swap fee unless another one is given to `new`
+const DEFAULT_FEE_BPS: u16 = 30;
+const MAX_FEE_BPS: u16 = 1000;
+const BPS_DENOMINATOR: u128 = 10_000;
+/// slippage tolerated by deposit_a and deposit_b called without a least output, 0.50% unless the owner changes it
+const DEFAULT_SLIPPAGE_BPS: u16 = 50;
+/// part of the output reserve in basis points get_max_input lets a swap take when the pool has no floor
+const DEFAULT_MAX_DRAIN_BPS: u16 = 5000;
+/// shares of the first deposit into an empty pool that nobody owns, so the price of a share can't be inflated cheaply
+const MINIMUM_LIQUIDITY: Balance = 1000;
+/// the most decimals of each reserve `set_ratio_precision` lets the ratio keep
+const MAX_RATIO_PRECISION: u8 = 18;
+/// fees per share in the liquidity provider fee accumulators are scaled by this factor
+const FEE_PER_SHARE_PRECISION: u128 = 1_000_000_000_000_000_000_000_000;
+/// gas attached to the cross-contract calls, each callback gets enough for the calls it makes in turn
+const GAS_FOR_VIEW: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_TRANSFER_FROM: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_REGISTER_AMM: Gas = Gas(15_000_000_000_000);
+const GAS_FOR_ON_FLASH_SWAP: Gas = Gas(30_000_000_000_000);
+const GAS_FOR_NEAR_DEPOSIT: Gas = Gas(10_000_000_000_000);
+/// a callback that makes no further calls, unless the owner changes it with `set_callback_gas`
+const GAS_FOR_CALLBACK: Gas = Gas(10_000_000_000_000);
+/// the most `set_callback_gas` accepts, so a swap with the oracle guard still fits in a transaction
+const MAX_CALLBACK_GAS: Gas = Gas(100_000_000_000_000);
+/// the fees of scheduling one more call, including the data receipt of its callback
+const GAS_FOR_RECEIPT: Gas = Gas(15_000_000_000_000);
+/// prices in the cumulative oracle are reserve ratios scaled by this factor
+const PRICE_PRECISION: u128 = 1_000_000_000_000;
+/// swaps kept for get_recent_trades, older ones are overwritten
+const TRADE_HISTORY_SIZE: u64 = 50;
+/// blocks after which get_pool_health reports a lock as stale, the callbacks of a call finish within a few
+const STALE_LOCK_BLOCKS: u64 = 100;
 
 #[ext_contract(ext_token)]
 trait ExtToken {
     fn get_info(&self) -> (String, u8);
     fn register_amm(&mut self, sender_id: AccountId, amount: Balance);
     fn transfer_from(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: Balance);
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+}
+
+/// Interface of a `flash_swap` receiver, which has to send `repay` of `token_out` back to the pool
+/// before the promise it returns resolves.
+#[ext_contract(ext_flash_receiver)]
+trait ExtFlashReceiver {
+    fn on_flash_swap(&mut self, token_out: AccountId, amount: U128, repay: U128, msg: String);
+}
+
+/// Interface of the wrapped NEAR contract, which mints wNEAR for the attached deposit to the caller.
+#[ext_contract(ext_wrap)]
+trait ExtWrap {
+    fn near_deposit(&mut self);
+}
+
+/// Interface of a price feed, the price is whole `quote` tokens per whole `base` token scaled by
+/// PRICE_PRECISION.
+#[ext_contract(ext_oracle)]
+trait ExtOracle {
+    fn get_price(&self, base: AccountId, quote: AccountId) -> U128;
 }
 
 #[ext_contract(ext_self)]
 trait ExtSelf {
-    fn callback_get_info(&mut self, contract_id: AccountId, #[callback] val: (String, u8));
+    fn callback_get_info(
+        &mut self,
+        contract_id: AccountId,
+        #[callback_result] val: Result<(String, u8), PromiseError>,
+    );
+    fn callback_ft_deposit_balance(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        min_out: Balance,
+        referrer: Option<AccountId>,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128>;
+    fn callback_oracle_swap(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        min_out: Balance,
+        referrer: Option<AccountId>,
+        #[callback_result] price: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128>;
+    fn callback_oracle_transfer(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        #[callback_result] price: Result<U128, PromiseError>,
+    ) -> U128;
     fn callback_ft_deposit(
         &mut self,
         a_ticker_after: Balance,
         b_ticker_after: Balance,
         contract_id: AccountId,
         receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        min_out: Balance,
+        balance_before: Balance,
+        referrer: Option<AccountId>,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128>;
+    fn callback_update_tickers(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        amount: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> U128;
+    fn callback_swap_output(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        referrer: Option<AccountId>,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> U128;
+    fn callback_swap_refund(
+        &mut self,
+        token_in: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    );
+    fn callback_rebalance(&mut self, a_target: Balance, b_target: Balance) -> (U128, U128);
+    fn callback_near_deposit(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        #[callback_result] deposit_result: Result<(), PromiseError>,
+    ) -> PromiseOrValue<U128>;
+    fn callback_close_pool(&mut self) -> (U128, U128);
+    fn callback_collect_fees(
+        &mut self,
+        token: AccountId,
+        owner_id: AccountId,
         amount: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    );
+    fn callback_collect_protocol_fees(
+        &mut self,
+        token: AccountId,
+        amount: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    );
+    fn callback_claim_referral_fees(
+        &mut self,
+        account_id: AccountId,
+        a_amount: Balance,
+        b_amount: Balance,
+    );
+    fn callback_claim_fees(&mut self, account_id: AccountId, a_amount: Balance, b_amount: Balance);
+    fn callback_zap_in(
+        &mut self,
+        account_id: AccountId,
+        token_in: AccountId,
+        amount_in: Balance,
+        min_shares: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> U128;
+    fn callback_add_liquidity(
+        &mut self,
+        account_id: AccountId,
+        shares: Balance,
+        a_amount: Balance,
+        b_amount: Balance,
+        #[callback_result] a_result: Result<(), PromiseError>,
+        #[callback_result] b_result: Result<(), PromiseError>,
+    );
+    fn callback_remove_liquidity(
+        &mut self,
+        account_id: AccountId,
+        a_amount: Balance,
+        b_amount: Balance,
+        #[callback_result] a_result: Result<(), PromiseError>,
+        #[callback_result] b_result: Result<(), PromiseError>,
+    );
+    fn callback_register_amm(
+        &mut self,
+        token: AccountId,
+        #[callback_result] result: Result<(), PromiseError>,
     );
-    fn callback_update_tickers(&mut self, a_ticker_after: Balance, b_ticker_after: Balance);
+    fn callback_owner_balances(
+        &self,
+        #[callback_result] a_balance: Result<U128, PromiseError>,
+        #[callback_result] b_balance: Result<U128, PromiseError>,
+    ) -> (U128, U128);
+    fn callback_balance_limited_output(
+        &self,
+        token_in: AccountId,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> U128;
+    fn callback_skim(
+        &mut self,
+        #[callback_result] a_balance: Result<U128, PromiseError>,
+        #[callback_result] b_balance: Result<U128, PromiseError>,
+    ) -> (U128, U128);
+    fn callback_sync(
+        &mut self,
+        #[callback_result] a_balance: Result<U128, PromiseError>,
+        #[callback_result] b_balance: Result<U128, PromiseError>,
+    ) -> (U128, U128);
+    fn callback_flash_swap_lend(
+        &mut self,
+        token_out: AccountId,
+        amount: Balance,
+        receiver: AccountId,
+        msg: String,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<bool>;
+    fn callback_flash_swap(
+        &mut self,
+        token_out: AccountId,
+        amount: Balance,
+        fee: Balance,
+        balance_before: Balance,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> bool;
+}
+
+/// prefixes of the collections, each is the index of its variant, so new ones only go at the end
+#[derive(BorshSerialize, BorshStorageKey)]
+enum StorageKey {
+    Shares,
+    FlashReceivers,
+    Trades,
+    Allowlist,
+    ReferralFees,
+    RewardDebts,
+}
+
+/// where a liquidity provider stands in the fee accumulators: the fees per share its shares were last
+/// settled at, times its shares and scaled by FEE_PER_SHARE_PRECISION, and the fees settled but not claimed
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+struct RewardDebt {
+    a_debt: U256,
+    b_debt: U256,
+    a_pending: Balance,
+    b_pending: Balance,
+}
+
+/// what a prospective swap would do to the pool, amounts are in minimum units and prices are
+/// the output token per input token scaled by 10^12
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapQuote {
+    pub amount_out: U128,
+    pub price_before: U128,
+    pub price_after: U128,
+    pub price_impact_bps: u16,
+    pub fee_amount: U128,
+}
+
+/// a completed swap, amounts are in minimum units
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeRecord {
+    pub timestamp: u64,
+    pub token_in: AccountId,
+    pub amount_in: U128,
+    pub amount_out: U128,
+    pub account_id: AccountId,
+}
+
+/// named view of both sides of the pool, reserves are in minimum units
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolInfo {
+    pub a_account: AccountId,
+    pub a_symbol: String,
+    pub a_reserve: U128,
+    pub a_decimals: Option<u8>,
+    pub b_account: AccountId,
+    pub b_symbol: String,
+    pub b_reserve: U128,
+    pub b_decimals: Option<u8>,
+    pub ratio: U128,
+}
+
+/// status flags of the pool for monitoring
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolHealth {
+    pub paused: bool,
+    pub initialized: bool,
+    /// a call waits for its callbacks, which is normal while swaps and liquidity changes are in flight
+    pub locked: bool,
+    /// the lock is held for more than STALE_LOCK_BLOCKS, so a callback was lost, see `force_unlock`
+    pub lock_stale: bool,
+    /// the pool is initialized, not paused and not stuck in a stale lock
+    pub healthy: bool,
+}
+
+/// administrative settings of the pool, max trades and reserve floors are in whole tokens
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    pub owner_id: AccountId,
+    pub fee_bps: u16,
+    pub paused: bool,
+    pub paused_a_to_b: bool,
+    pub paused_b_to_a: bool,
+    pub max_trade_a: Option<U128>,
+    pub max_trade_b: Option<U128>,
+    pub min_reserve_a: U128,
+    pub min_reserve_b: U128,
+    pub protocol_fee_bps: u16,
+    pub fee_recipient: AccountId,
+    pub referral_bps: u16,
+    pub breaker_bps: u16,
+    pub allowlist_enabled: bool,
+    pub wrap_contract_id: Option<AccountId>,
+    pub oracle_account: Option<AccountId>,
+    pub oracle_tolerance_bps: u16,
+    pub default_slippage_bps: u16,
+    pub callback_gas: Gas,
+    pub ratio_precision: u8,
 }
 
 #[near_bindgen]
 #[derive(PanicOnDefault, BorshDeserialize, BorshSerialize)]
 pub struct Contract {
     owner_id: AccountId,
-    ratio: u128,
+    //proposed owner, who becomes the owner after calling accept_owner
+    pending_owner: Option<AccountId>,
+    //nanosecond timestamp after which the proposal can no longer be accepted
+    pending_owner_expiry: u64,
+    //product of the reserves in whole tokens, for display, wider than u128 for large supplies
+    ratio: U256,
+    //decimals of each reserve the ratio keeps, so it is in units of 10^-(2 * ratio_precision)
+    ratio_precision: u8,
+    //X * Y = K of the reserves in minimum units, swaps are priced against it
+    k: U256,
     //total A token number
     a_ticker: Balance,
     a_contract_id: AccountId,
     a_contract_name: String,
-    a_contract_decimals: u8,
+    //None until the token metadata arrives
+    a_contract_decimals: Option<u8>,
     //total B token number
     b_ticker: Balance,
     b_contract_id: AccountId,
     b_contract_name: String,
-    b_contract_decimals: u8,
+    b_contract_decimals: Option<u8>,
+    //whether the names and decimals of both tokens have arrived
+    metadata_loaded: bool,
+    //whether the pool's wallets in the token contracts were created with register_amm
+    registered_a: bool,
+    registered_b: bool,
+    //swap fee in basis points, charged on the input amount
+    fee_bps: u16,
+    //launch fee going linearly from fee_start_bps at fee_ramp_start to fee_end_bps at fee_ramp_end
+    //(nanosecond timestamps), fee_ramp_end is 0 without a ramp
+    fee_start_bps: u16,
+    fee_end_bps: u16,
+    fee_ramp_start: u64,
+    fee_ramp_end: u64,
+    //liquidity provider part of the swap fees not claimed yet, held by the contract but kept out of
+    //the tickers
+    a_fees: Balance,
+    b_fees: Balance,
+    //liquidity provider fees earned per share since the pool started, scaled by FEE_PER_SHARE_PRECISION,
+    //and for each provider the part of them its shares are already settled for
+    a_fee_per_share: U256,
+    b_fee_per_share: U256,
+    reward_debts: LookupMap<AccountId, RewardDebt>,
+    //part of the swap fee in basis points owed to fee_recipient, tracked apart from the fees above
+    protocol_fee_bps: u16,
+    fee_recipient: AccountId,
+    a_protocol_fees: Balance,
+    b_protocol_fees: Balance,
+    //part of the rest of the swap fee in basis points owed to the referrer a swap names, the totals
+    //of (A, B) still owed to each referrer are kept apart from the fees above as well
+    referral_bps: u16,
+    referral_fees: LookupMap<AccountId, (Balance, Balance)>,
+    a_referral_fees: Balance,
+    b_referral_fees: Balance,
+    //liquidity provider shares of the reserves
+    shares: LookupMap<AccountId, Balance>,
+    total_shares: Balance,
+    //bytes an entry in the shares map and one in the reward debts take, which the account pays for on
+    //registration
+    account_storage_usage: StorageUsage,
+    //swaps and liquidity changes are blocked while paused
+    paused: bool,
+    //swaps in one direction only, A for B and B for A, are blocked while set
+    paused_a_to_b: bool,
+    paused_b_to_a: bool,
+    //largest amount of A or B in whole tokens a single swap may sell, None for no limit
+    max_trade_a: Option<Balance>,
+    max_trade_b: Option<Balance>,
+    //smallest reserve of A or B in whole tokens a swap may leave behind, 0 for no floor
+    min_reserve_a: Balance,
+    min_reserve_b: Balance,
+    //largest change of a reserve in basis points a single ticker update may make, 0 for no limit
+    breaker_bps: u16,
+    //set while a swap, liquidity change, fee claim or owner deposit waits for its callbacks, so the
+    //tickers can't be used stale and no outflow moves the balances a swap reads
+    locked: bool,
+    //block height at which the lock was last taken
+    locked_at: u64,
+    //sums of the prices (B per A and A per B, in minimum units) times the seconds they lasted
+    price_cumulative_a: u128,
+    price_cumulative_b: u128,
+    last_price_timestamp: u64,
+    //block height at which a swap or owner deposit last committed the tickers
+    last_update_block: u64,
+    //contracts the owner trusts to repay a flash swap
+    flash_receivers: LookupSet<AccountId>,
+    //ring buffer of the last TRADE_HISTORY_SIZE swaps, trade_head is the index the next one goes to
+    trades: Vector<TradeRecord>,
+    trade_head: u64,
+    //accounts allowed to swap with deposit_a, deposit_b and ft_transfer_call, None while anyone may
+    allowlist: Option<LookupSet<AccountId>>,
+    //lifetime swap inputs of A and B in minimum units, fee included, owner deposits and liquidity are not counted
+    volume_a_in: Balance,
+    volume_b_in: Balance,
+    //the pool token that is wrapped NEAR, which deposit_near swaps native NEAR as, None without one
+    wrap_contract_id: Option<AccountId>,
+    //price feed swaps are checked against, and how far in basis points the spot price may be off it
+    oracle_account: Option<AccountId>,
+    oracle_tolerance_bps: u16,
+    //slippage in basis points tolerated by deposit_a and deposit_b called without a least output
+    default_slippage_bps: u16,
+    //nanosecond timestamp of new, or of the migration for pools of the first release
+    created_at: u64,
+    //gas attached to the callbacks that make no further calls, see set_callback_gas
+    callback_gas: Gas,
 }
 
 #[near_bindgen]
@@ -48,56 +512,216 @@ impl Contract {
     /// Input are the address of the contract owner and the addresses of two tokens (hereinafter token A and token B).
     /// requests and stores the metadata of tokens (name, decimals) and
     /// Creates wallets for tokens А & В.
+    /// `a_initial` and `b_initial` are the starting reserves in minimum units, moved from the owner
+    /// and setting the initial price.
+    /// `fee_bps` is the swap fee in basis points, 30 (0.30%) when omitted.
+    /// The owner gets all sqrt(a * b) initial shares, with no MINIMUM_LIQUIDITY locked as in a first
+    /// `add_liquidity`: `close_pool`, `rebalance` and the owner withdrawals require the owner to hold
+    /// every share, and `storage_unregister` won't burn the last shares while the pool holds reserves.
     #[init]
-    pub fn new(owner_id: AccountId, a_contract_id: AccountId, b_contract_id: AccountId) -> Self {
+    pub fn new(
+        owner_id: AccountId,
+        a_contract_id: AccountId,
+        b_contract_id: AccountId,
+        a_initial: U128,
+        b_initial: U128,
+        fee_bps: Option<u16>,
+    ) -> Self {
         require!(!env::state_exists(), "The contract has been initialized");
+        require!(a_contract_id != b_contract_id, "tokens must differ");
+        require!(
+            a_initial.0 > 0 && b_initial.0 > 0,
+            "initial reserves must be positive"
+        );
+        let fee_bps = fee_bps.unwrap_or(DEFAULT_FEE_BPS);
+        require!(fee_bps <= MAX_FEE_BPS, "fee_bps must not exceed 1000");
 
-        let this = Self {
+        let mut this = Self {
             owner_id: owner_id.clone(),
-            ratio: 0,
-            a_ticker: A_TICKER,
+            pending_owner: None,
+            pending_owner_expiry: 0,
+            ratio: U256::zero(),
+            ratio_precision: 0,
+            k: U256::from(a_initial.0) * U256::from(b_initial.0),
+            a_ticker: a_initial.0,
             a_contract_id,
             a_contract_name: "".into(),
-            a_contract_decimals: 1,
-            b_ticker: B_TICKER,
+            a_contract_decimals: None,
+            b_ticker: b_initial.0,
             b_contract_id,
             b_contract_name: "".into(),
-            b_contract_decimals: 1,
+            b_contract_decimals: None,
+            metadata_loaded: false,
+            registered_a: false,
+            registered_b: false,
+            fee_bps,
+            fee_start_bps: 0,
+            fee_end_bps: 0,
+            fee_ramp_start: 0,
+            fee_ramp_end: 0,
+            a_fees: 0,
+            b_fees: 0,
+            a_fee_per_share: U256::zero(),
+            b_fee_per_share: U256::zero(),
+            reward_debts: LookupMap::new(StorageKey::RewardDebts),
+            protocol_fee_bps: 0,
+            fee_recipient: owner_id.clone(),
+            a_protocol_fees: 0,
+            b_protocol_fees: 0,
+            referral_bps: 0,
+            referral_fees: LookupMap::new(StorageKey::ReferralFees),
+            a_referral_fees: 0,
+            b_referral_fees: 0,
+            shares: LookupMap::new(StorageKey::Shares),
+            total_shares: 0,
+            account_storage_usage: 0,
+            paused: false,
+            paused_a_to_b: false,
+            paused_b_to_a: false,
+            max_trade_a: None,
+            max_trade_b: None,
+            min_reserve_a: 0,
+            min_reserve_b: 0,
+            breaker_bps: 0,
+            locked: false,
+            locked_at: 0,
+            price_cumulative_a: 0,
+            price_cumulative_b: 0,
+            last_price_timestamp: env::block_timestamp(),
+            last_update_block: env::block_height(),
+            flash_receivers: LookupSet::new(StorageKey::FlashReceivers),
+            trades: Vector::new(StorageKey::Trades),
+            trade_head: 0,
+            allowlist: None,
+            volume_a_in: 0,
+            volume_b_in: 0,
+            wrap_contract_id: None,
+            oracle_account: None,
+            oracle_tolerance_bps: 0,
+            default_slippage_bps: DEFAULT_SLIPPAGE_BPS,
+            created_at: env::block_timestamp(),
+            callback_gas: GAS_FOR_CALLBACK,
         };
+        // the initial reserves are the owner's liquidity
+        let initial_shares = (U256::from(a_initial.0) * U256::from(b_initial.0))
+            .integer_sqrt()
+            .as_u128();
+        this.measure_account_storage_usage();
+        this.shares.insert(&owner_id, &initial_shares);
+        this.total_shares = initial_shares;
         // The method requests and stores the metadata of tokens (name, decimals)
-        ext_token::ext(this.a_contract_id.clone()).get_info().then(
-            ext_self::ext(env::current_account_id()).callback_get_info(this.a_contract_id.clone()),
-        );
-        ext_token::ext(this.b_contract_id.clone()).get_info().then(
-            ext_self::ext(env::current_account_id()).callback_get_info(this.b_contract_id.clone()),
-        );
+        this.fetch_metadata();
         // Creates wallets for tokens А & В.
-        ext_token::ext(this.a_contract_id.clone()).register_amm(owner_id.clone(), this.a_ticker);
-        ext_token::ext(this.b_contract_id.clone()).register_amm(owner_id, this.b_ticker);
+        this.register_amm(this.a_contract_id.clone(), this.a_ticker);
+        this.register_amm(this.b_contract_id.clone(), this.b_ticker);
+        events::PoolCreated {
+            owner_id: &this.owner_id,
+            a_contract_id: &this.a_contract_id,
+            b_contract_id: &this.b_contract_id,
+            a_initial,
+            b_initial,
+        }
+        .emit();
         this
     }
 
-    pub fn callback_get_info(&mut self, contract_id: AccountId, #[callback] val: (String, u8)) {
+    /// Creates the wallet for `token` again, for when `register_amm` failed at initialization.
+    /// `amount` is moved from the owner, normally the initial reserve the failed registration didn't move.
+    pub fn register_with_token(&mut self, token: AccountId, amount: U128) {
+        self.assert_owner();
+        let registered = if token == self.a_contract_id {
+            self.registered_a
+        } else if token == self.b_contract_id {
+            self.registered_b
+        } else {
+            env::panic_str("token is not in the pool")
+        };
+        require!(!registered, "token is already registered");
+        self.register_amm(token, amount.0);
+    }
+
+    pub fn callback_register_amm(
+        &mut self,
+        token: AccountId,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        if result.is_err() {
+            log!("Registration with {} failed", token);
+            return;
+        }
+        if token == self.a_contract_id {
+            self.registered_a = true;
+        } else {
+            self.registered_b = true;
+        }
+    }
+
+    /// Requests the metadata of both tokens again, for when a fetch at initialization failed.
+    /// Metadata that is loaded already is kept, `set_token_decimals` corrects it.
+    pub fn refresh_metadata(&mut self) {
+        self.assert_owner();
+        self.fetch_metadata();
+    }
+
+    /// Overrides the stored decimals of one of the pool's tokens, for tokens that corrected
+    /// their metadata after the pool fetched it, and recomputes the ratio with them.
+    pub fn set_token_decimals(&mut self, token: AccountId, decimals: u8) {
+        self.assert_owner();
+        if token == self.a_contract_id {
+            self.a_contract_decimals = Some(decimals);
+        } else {
+            require!(token == self.b_contract_id, "token is not in the pool");
+            self.b_contract_decimals = Some(decimals);
+        }
+        self.calc_ratio();
+    }
+
+    pub fn callback_get_info(
+        &mut self,
+        contract_id: AccountId,
+        #[callback_result] val: Result<(String, u8), PromiseError>,
+    ) {
         require!(
             env::predecessor_account_id() == env::current_account_id(),
             "only support in self"
         );
+        let val = match val {
+            Ok(val) => val,
+            Err(_) => {
+                log!("Metadata fetch for {} failed", contract_id);
+                return;
+            }
+        };
+        let loaded = if contract_id == self.a_contract_id {
+            !self.a_contract_name.is_empty() && self.a_contract_decimals.is_some()
+        } else {
+            !self.b_contract_name.is_empty() && self.b_contract_decimals.is_some()
+        };
+        if loaded {
+            log!("Metadata of {} is already loaded, ignoring it", contract_id);
+            return;
+        }
         log!("Fill additional info for {}", val.0);
         if contract_id == self.a_contract_id {
             self.a_contract_name = val.0;
-            self.a_contract_decimals = val.1;
+            self.a_contract_decimals = Some(val.1);
         } else if contract_id == self.b_contract_id {
             self.b_contract_name = val.0;
-            self.b_contract_decimals = val.1;
+            self.b_contract_decimals = Some(val.1);
         }
+        self.metadata_loaded = !self.a_contract_name.is_empty() && !self.b_contract_name.is_empty();
         self.calc_ratio();
     }
 
     pub fn get_info(
         &self,
     ) -> (
-        (AccountId, String, Balance, u8),
-        (AccountId, String, Balance, u8),
+        (AccountId, String, Balance, Option<u8>),
+        (AccountId, String, Balance, Option<u8>),
     ) {
         (
             (
@@ -115,133 +739,8197 @@ impl Contract {
         )
     }
 
-    pub fn get_ratio(&self) -> u128 {
-        self.ratio
+    pub fn get_pool_info(&self) -> PoolInfo {
+        PoolInfo {
+            a_account: self.a_contract_id.clone(),
+            a_symbol: self.a_contract_name.clone(),
+            a_reserve: self.a_ticker.into(),
+            a_decimals: self.a_contract_decimals,
+            b_account: self.b_contract_id.clone(),
+            b_symbol: self.b_contract_name.clone(),
+            b_reserve: self.b_ticker.into(),
+            b_decimals: self.b_contract_decimals,
+            ratio: self.display_ratio().into(),
+        }
     }
 
-    fn calc_ratio(&mut self) {
-        let a_num = self.a_ticker / 10_u128.pow(self.a_contract_decimals as u32);
-        let b_num = self.b_ticker / 10_u128.pow(self.b_contract_decimals as u32);
-        //X * Y = K , K is some constant value
-        self.ratio = a_num * b_num;
+    /// When the pool was created, as a nanosecond timestamp. The first release didn't record it, so its
+    /// pools report the time of their migration.
+    pub fn get_created_at(&self) -> u64 {
+        self.created_at
     }
 
-    /// The user can transfer a certain number of tokens A to the contract account and 
-    /// in return must receive a certain number of tokens B (similarly in the other direction).
-    /// The contract supports a certain ratio of tokens A and B. X * Y = K 
-    /// K is some constant value, X and Y are the number of tokens A and B respectively.
+    /// The semantic version of the deployed code.
+    pub fn get_version(&self) -> String {
+        VERSION.to_string()
+    }
+
+    pub fn get_config(&self) -> Config {
+        Config {
+            owner_id: self.owner_id.clone(),
+            fee_bps: self.active_fee_bps(),
+            paused: self.paused,
+            paused_a_to_b: self.paused_a_to_b,
+            paused_b_to_a: self.paused_b_to_a,
+            max_trade_a: self.max_trade_a.map(U128),
+            max_trade_b: self.max_trade_b.map(U128),
+            min_reserve_a: self.min_reserve_a.into(),
+            min_reserve_b: self.min_reserve_b.into(),
+            protocol_fee_bps: self.protocol_fee_bps,
+            fee_recipient: self.fee_recipient.clone(),
+            referral_bps: self.referral_bps,
+            breaker_bps: self.breaker_bps,
+            allowlist_enabled: self.allowlist.is_some(),
+            wrap_contract_id: self.wrap_contract_id.clone(),
+            oracle_account: self.oracle_account.clone(),
+            oracle_tolerance_bps: self.oracle_tolerance_bps,
+            default_slippage_bps: self.default_slippage_bps,
+            callback_gas: self.callback_gas,
+            ratio_precision: self.ratio_precision,
+        }
+    }
+
+    /// The (A, B) token contracts the pool pairs.
+    pub fn get_tokens(&self) -> (AccountId, AccountId) {
+        (self.a_contract_id.clone(), self.b_contract_id.clone())
+    }
+
+    /// The (A, B) reserves in minimum units, as strings so web clients don't lose precision.
+    pub fn get_reserves(&self) -> (U128, U128) {
+        (self.a_ticker.into(), self.b_ticker.into())
+    }
+
+    /// The (A, B) reserves in whole tokens for display, the fractional part is truncated,
+    /// so a reserve below one token shows as 0.
+    pub fn get_reserves_human(&self) -> (U128, U128) {
+        let (a_decimals, b_decimals) = self.decimals();
+        (
+            (self.a_ticker / 10_u128.pow(a_decimals as u32)).into(),
+            (self.b_ticker / 10_u128.pow(b_decimals as u32)).into(),
+        )
+    }
+
+    /// Whether the metadata of (A, B) has arrived, so clients can tell a pool that is still initializing.
+    pub fn get_metadata_status(&self) -> (bool, bool) {
+        (
+            !self.a_contract_name.is_empty() && self.a_contract_decimals.is_some(),
+            !self.b_contract_name.is_empty() && self.b_contract_decimals.is_some(),
+        )
+    }
+
+    /// The product of the reserves in whole tokens, or with `ratio_precision` decimals of each, so in
+    /// units of 10^-(2 * ratio_precision). Capped at the largest u128 for pools of supplies too large
+    /// for that, `get_k` stays exact.
+    pub fn get_ratio(&self) -> U128 {
+        self.display_ratio().into()
+    }
+
+    /// The constant product of the reserves in minimum units, as a decimal string since it
+    /// usually needs more than 128 bits.
+    pub fn get_k(&self) -> String {
+        self.k.to_string()
+    }
+
+    /// The pause, initialization and lock flags of the pool. K is recomputed from the tickers whenever
+    /// they change, so it can't drift from them, and comparing the tickers with the token balances
+    /// takes `skim` or `sync`, which call the tokens.
+    /// A paused pool, e.g. by the circuit breaker, or a lock held for more than STALE_LOCK_BLOCKS is
+    /// unhealthy, the lock of a call in flight is not.
+    pub fn get_pool_health(&self) -> PoolHealth {
+        let initialized = self.check_initialized().is_ok();
+        let lock_stale = self.locked && env::block_height() > self.locked_at + STALE_LOCK_BLOCKS;
+        PoolHealth {
+            paused: self.paused,
+            initialized,
+            locked: self.locked,
+            lock_stale,
+            healthy: initialized && !self.paused && !lock_stale,
+        }
+    }
+
+    /// The price of one whole A in whole B as `(b_per_a_numerator, b_per_a_denominator)`,
+    /// reduced to lowest terms so callers can divide without floating point.
+    pub fn get_spot_price(&self) -> (U128, U128) {
+        let (a_decimals, b_decimals) = self.decimals();
+        let (numerator, denominator) = if a_decimals >= b_decimals {
+            let scale = 10_u128.pow((a_decimals - b_decimals) as u32);
+            (
+                self.b_ticker.checked_mul(scale).expect("ticker overflow"),
+                self.a_ticker,
+            )
+        } else {
+            let scale = 10_u128.pow((b_decimals - a_decimals) as u32);
+            (
+                self.b_ticker,
+                self.a_ticker.checked_mul(scale).expect("ticker overflow"),
+            )
+        };
+        require!(denominator > 0, "pool not initialized");
+        let divisor = gcd(numerator, denominator);
+        ((numerator / divisor).into(), (denominator / divisor).into())
+    }
+
+    /// The swap fee charged now, in basis points.
+    pub fn get_fee(&self) -> u16 {
+        self.active_fee_bps()
+    }
+
+    /// Changes the swap fee, in basis points. It replaces a fee ramp that is set.
+    /// Requires an attached deposit of exactly 1 yoctoNEAR, as the other sensitive owner methods do,
+    /// so that only a full access key can call it.
     #[payable]
-    pub fn deposit_a(&mut self, amount: Balance) {
-        let sender_id = env::predecessor_account_id();
-        let decimal = 10_u128.pow(self.a_contract_decimals as u32);
-        let a_amount = amount * decimal;
-        let a_ticker_after = a_amount + self.a_ticker;
-        let b_ticker_after = self.ratio
-            / (a_ticker_after / decimal)
-            * 10_u128.pow(self.b_contract_decimals as u32);
-        let b_amount = self.b_ticker - b_ticker_after;
-        let next_contract = self.b_contract_id.clone();
-        ext_token::ext(self.a_contract_id.clone())
-            .transfer_from(sender_id.clone(), env::current_account_id(), a_amount)
-            .then(
-                ext_self::ext(env::current_account_id()).callback_ft_deposit(
-                    a_ticker_after,
-                    b_ticker_after,
-                    next_contract,
-                    sender_id,
-                    b_amount,
-                ),
-            );
+    pub fn set_fee(&mut self, fee_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(fee_bps <= MAX_FEE_BPS, "fee_bps must not exceed 1000");
+        self.fee_bps = fee_bps;
+        self.fee_ramp_end = 0;
     }
 
-    /// The owner of the contract can transfer a certain amount of tokens A or B to the contract account, 
-    /// thereby changing the ratio K.
+    /// Charges `fee_start_bps` until `ramp_start`, then a fee moving linearly to `fee_end_bps` at
+    /// `ramp_end`, which stays the fee from then on. The ramp times are nanosecond timestamps.
+    /// Requires 1 yoctoNEAR, as does `set_fee`.
     #[payable]
-    pub fn deposit_a_by_owner(&mut self, amount: Balance) {
+    pub fn set_fee_ramp(
+        &mut self,
+        fee_start_bps: u16,
+        fee_end_bps: u16,
+        ramp_start: u64,
+        ramp_end: u64,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
         require!(
-            env::predecessor_account_id() == self.owner_id,
-            "only support to call by itself"
+            fee_start_bps <= MAX_FEE_BPS && fee_end_bps <= MAX_FEE_BPS,
+            "fee_bps must not exceed 1000"
         );
-        let a_amount = amount * 10_u128.pow(self.a_contract_decimals as u32);
-        let a_ticker_after = a_amount + self.a_ticker;
-        let b_ticker_after = self.b_ticker;
+        require!(ramp_start < ramp_end, "the ramp must end after it starts");
+        self.fee_start_bps = fee_start_bps;
+        self.fee_end_bps = fee_end_bps;
+        self.fee_ramp_start = ramp_start;
+        self.fee_ramp_end = ramp_end;
+        self.fee_bps = fee_end_bps;
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// Queries how much of (A, B) the owner holds in the token contracts, in minimum units,
+    /// e.g. to verify the `register_amm` transfers of the initial reserves went through.
+    pub fn get_owner_balances(&self) -> Promise {
         ext_token::ext(self.a_contract_id.clone())
-            .transfer_from(self.owner_id.clone(), env::current_account_id(), a_amount)
+            .with_static_gas(GAS_FOR_VIEW)
+            .ft_balance_of(self.owner_id.clone())
+            .and(
+                ext_token::ext(self.b_contract_id.clone())
+                    .with_static_gas(GAS_FOR_VIEW)
+                    .ft_balance_of(self.owner_id.clone()),
+            )
             .then(
                 ext_self::ext(env::current_account_id())
-                    .callback_update_tickers(a_ticker_after, b_ticker_after),
-            );
+                    .with_static_gas(self.callback_gas)
+                    .callback_owner_balances(),
+            )
     }
 
-    /// in the opposite direction 
-    #[payable]
-    pub fn deposit_b(&mut self, amount: Balance) {
-        let sender_id = env::predecessor_account_id();
-        let decimal = 10_u128.pow(self.b_contract_decimals as u32);
-        let b_amount = amount * decimal;
-        let b_ticker_after = b_amount + self.b_ticker;
-        let a_ticker_after = self.ratio
-            / (b_ticker_after / decimal)
-            * 10_u128.pow(self.a_contract_decimals as u32);
-        let a_amount = self.a_ticker - a_ticker_after;
-        let next_contract = self.a_contract_id.clone();
-        ext_token::ext(self.b_contract_id.clone())
-            .transfer_from(sender_id.clone(), env::current_account_id(), b_amount)
+    pub fn callback_owner_balances(
+        &self,
+        #[callback_result] a_balance: Result<U128, PromiseError>,
+        #[callback_result] b_balance: Result<U128, PromiseError>,
+    ) -> (U128, U128) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        require!(
+            a_balance.is_ok() && b_balance.is_ok(),
+            "balance query failed"
+        );
+        (a_balance.unwrap(), b_balance.unwrap())
+    }
+
+    /// Queries the balance `account_id` holds of `token_in`, which is all the pool can pull from it in a
+    /// swap, and quotes the most of the other token it can get for it, in minimum units. The input is
+    /// capped by the max trade size and the reserve floor as well, so a UI can cap the swap up front.
+    pub fn get_balance_limited_output(
+        &self,
+        token_in: AccountId,
+        account_id: AccountId,
+    ) -> Promise {
+        // runs the same checks as the plain quote
+        self.get_amount_out(token_in.clone(), U128(0));
+        ext_token::ext(token_in.clone())
+            .with_static_gas(GAS_FOR_VIEW)
+            .ft_balance_of(account_id)
             .then(
-                ext_self::ext(env::current_account_id()).callback_ft_deposit(
-                    a_ticker_after,
-                    b_ticker_after,
-                    next_contract,
-                    sender_id,
-                    a_amount,
-                ),
-            );
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_balance_limited_output(token_in),
+            )
     }
 
-    #[payable]
-    pub fn deposit_b_by_owner(&mut self, amount: Balance) {
+    pub fn callback_balance_limited_output(
+        &self,
+        token_in: AccountId,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> U128 {
         require!(
-            env::predecessor_account_id() == self.owner_id,
+            env::predecessor_account_id() == env::current_account_id(),
             "only support to call by itself"
         );
-        let b_amount = amount * 10_u128.pow(self.b_contract_decimals as u32);
-        let b_ticker_after = b_amount + self.b_ticker;
-        let a_ticker_after = self.a_ticker;
-        ext_token::ext(self.b_contract_id.clone())
-            .transfer_from(self.owner_id.clone(), env::current_account_id(), b_amount)
+        let balance = balance.expect("balance query failed").0;
+        let max_input = self.get_max_input(token_in.clone(), Some(BPS_DENOMINATOR as u16));
+        self.try_calc_swap_units(&token_in, balance.min(max_input.0))
+            .map_or(0, |swap| swap.4)
+            .into()
+    }
+
+    /// Adds tokens sent straight to the pool account, outside of any swap or deposit, to the reserves.
+    /// Only the owner can call it, since it holds the lock while the balances are queried, and the tokens
+    /// become part of the liquidity of all share holders.
+    /// The returned promise resolves to the amounts of A and B added.
+    pub fn skim(&mut self) -> Promise {
+        self.assert_owner();
+        // a swap in flight holds tokens that are not in the tickers yet
+        self.lock();
+        ext_token::ext(self.a_contract_id.clone())
+            .with_static_gas(GAS_FOR_VIEW)
+            .ft_balance_of(env::current_account_id())
+            .and(
+                ext_token::ext(self.b_contract_id.clone())
+                    .with_static_gas(GAS_FOR_VIEW)
+                    .ft_balance_of(env::current_account_id()),
+            )
             .then(
                 ext_self::ext(env::current_account_id())
-                    .callback_update_tickers(a_ticker_after, b_ticker_after),
-            );
+                    .with_static_gas(self.callback_gas)
+                    .callback_skim(),
+            )
     }
 
-    pub fn callback_ft_deposit(
+    pub fn callback_skim(
         &mut self,
-        a_ticker_after: Balance,
-        b_ticker_after: Balance,
-        contract_id: AccountId,
-        receiver_id: AccountId,
-        amount: Balance,
-    ) {
+        #[callback_result] a_balance: Result<U128, PromiseError>,
+        #[callback_result] b_balance: Result<U128, PromiseError>,
+    ) -> (U128, U128) {
         require!(
             env::predecessor_account_id() == env::current_account_id(),
             "only support to call by itself"
         );
-        ext_token::ext(contract_id)
-            .transfer_from(env::current_account_id(), receiver_id, amount)
+        self.locked = false;
+        let (a_balance, b_balance) = match (a_balance, b_balance) {
+            (Ok(a_balance), Ok(b_balance)) => (a_balance.0, b_balance.0),
+            _ => {
+                log!("Balance query failed, nothing skimmed");
+                return (U128(0), U128(0));
+            }
+        };
+        // the fees are held by the pool too, but are not part of the reserves
+        let a_excess = a_balance.saturating_sub(
+            self.a_ticker + self.a_fees + self.a_protocol_fees + self.a_referral_fees,
+        );
+        let b_excess = b_balance.saturating_sub(
+            self.b_ticker + self.b_fees + self.b_protocol_fees + self.b_referral_fees,
+        );
+        if a_excess > 0 || b_excess > 0 {
+            log!(
+                "Skimmed {} A and {} B into the reserves",
+                a_excess,
+                b_excess
+            );
+            self.update_price_cumulative();
+            self.a_ticker += a_excess;
+            self.b_ticker += b_excess;
+            self.calc_ratio();
+            events::Sync {
+                a_ticker: self.a_ticker.into(),
+                b_ticker: self.b_ticker.into(),
+            }
+            .emit();
+        }
+        (a_excess.into(), b_excess.into())
+    }
+
+    /// Releases the lock a call left set when its callback never ran to the end, e.g. it ran out of gas,
+    /// which blocks every swap and liquidity change, `sync` included. Only for a lock stuck like that:
+    /// released while a callback is still pending, the callback could set stale tickers.
+    /// Requires 1 yoctoNEAR.
+    #[payable]
+    pub fn force_unlock(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(self.locked, "the pool is not locked");
+        self.locked = false;
+        log!("The owner released the lock");
+    }
+
+    /// Sets the tickers to the pool's balances in the token contracts, less the fees it holds,
+    /// to recover from tickers that drifted from the balances, e.g. after a failed callback.
+    /// The returned promise resolves to the new tickers.
+    pub fn sync(&mut self) -> Promise {
+        self.assert_owner();
+        self.lock();
+        ext_token::ext(self.a_contract_id.clone())
+            .with_static_gas(GAS_FOR_VIEW)
+            .ft_balance_of(env::current_account_id())
+            .and(
+                ext_token::ext(self.b_contract_id.clone())
+                    .with_static_gas(GAS_FOR_VIEW)
+                    .ft_balance_of(env::current_account_id()),
+            )
             .then(
                 ext_self::ext(env::current_account_id())
-                    .callback_update_tickers(a_ticker_after, b_ticker_after),
-            );
+                    .with_static_gas(self.callback_gas)
+                    .callback_sync(),
+            )
     }
 
-    pub fn callback_update_tickers(&mut self, a_ticker_after: Balance, b_ticker_after: Balance) {
+    pub fn callback_sync(
+        &mut self,
+        #[callback_result] a_balance: Result<U128, PromiseError>,
+        #[callback_result] b_balance: Result<U128, PromiseError>,
+    ) -> (U128, U128) {
         require!(
             env::predecessor_account_id() == env::current_account_id(),
             "only support to call by itself"
         );
-        self.a_ticker = a_ticker_after;
-        self.b_ticker = b_ticker_after;
+        self.locked = false;
+        let (a_balance, b_balance) = match (a_balance, b_balance) {
+            (Ok(a_balance), Ok(b_balance)) => (a_balance.0, b_balance.0),
+            _ => {
+                log!("Balance query failed, tickers are not synced");
+                return (self.a_ticker.into(), self.b_ticker.into());
+            }
+        };
+        self.update_price_cumulative();
+        self.a_ticker =
+            a_balance.saturating_sub(self.a_fees + self.a_protocol_fees + self.a_referral_fees);
+        self.b_ticker =
+            b_balance.saturating_sub(self.b_fees + self.b_protocol_fees + self.b_referral_fees);
         self.calc_ratio();
+        events::Sync {
+            a_ticker: self.a_ticker.into(),
+            b_ticker: self.b_ticker.into(),
+        }
+        .emit();
+        (self.a_ticker.into(), self.b_ticker.into())
+    }
+
+    /// Proposes `new_owner` as the next owner, the transfer happens once they call `accept_owner`.
+    /// The proposal expires after OWNER_PROPOSAL_DURATION, a new one replaces it. Requires 1 yoctoNEAR.
+    #[payable]
+    pub fn propose_owner(&mut self, new_owner: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.pending_owner = Some(new_owner);
+        self.pending_owner_expiry = env::block_timestamp() + OWNER_PROPOSAL_DURATION;
+    }
+
+    /// Requires 1 yoctoNEAR.
+    #[payable]
+    pub fn accept_owner(&mut self) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        require!(
+            self.pending_owner.as_ref() == Some(&account_id),
+            "only the pending owner can accept"
+        );
+        require!(
+            env::block_timestamp() < self.pending_owner_expiry,
+            "the ownership proposal has expired"
+        );
+        self.owner_id = account_id;
+        self.pending_owner = None;
+    }
+
+    /// Stops swaps and liquidity changes in an emergency, the owner deposit methods keep working.
+    /// Requires 1 yoctoNEAR, as does `unpause`.
+    #[payable]
+    pub fn pause(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.paused = true;
+    }
+
+    #[payable]
+    pub fn unpause(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.paused = false;
+    }
+
+    /// Stops or resumes swaps of A for B only, e.g. to keep exits open while A depegs.
+    pub fn set_paused_a_to_b(&mut self, paused: bool) {
+        self.assert_owner();
+        self.paused_a_to_b = paused;
+    }
+
+    /// Stops or resumes swaps of B for A only.
+    pub fn set_paused_b_to_a(&mut self, paused: bool) {
+        self.assert_owner();
+        self.paused_b_to_a = paused;
+    }
+
+    /// Stops swaps in both directions, liquidity changes keep working.
+    pub fn pause_all(&mut self) {
+        self.assert_owner();
+        self.paused_a_to_b = true;
+        self.paused_b_to_a = true;
+    }
+
+    pub fn unpause_all(&mut self) {
+        self.assert_owner();
+        self.paused_a_to_b = false;
+        self.paused_b_to_a = false;
+    }
+
+    /// Restricts swaps to the accounts on the allowlist.
+    /// The list starts out with the accounts it had when it was last disabled.
+    pub fn enable_allowlist(&mut self) {
+        self.assert_owner();
+        if self.allowlist.is_none() {
+            self.allowlist = Some(LookupSet::new(StorageKey::Allowlist));
+        }
+    }
+
+    /// Lets anyone swap again, the accounts on the allowlist are kept for the next `enable_allowlist`.
+    pub fn disable_allowlist(&mut self) {
+        self.assert_owner();
+        self.allowlist = None;
+    }
+
+    pub fn add_to_allowlist(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.allowlist
+            .as_mut()
+            .expect("allowlist is not enabled")
+            .insert(&account_id);
+    }
+
+    pub fn remove_from_allowlist(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.allowlist
+            .as_mut()
+            .expect("allowlist is not enabled")
+            .remove(&account_id);
+    }
+
+    /// Caps how many whole A tokens a single swap may sell, `None` removes the cap.
+    pub fn set_max_trade_a(&mut self, max_trade: Option<U128>) {
+        self.assert_owner();
+        self.max_trade_a = max_trade.map(|max_trade| max_trade.0);
+    }
+
+    /// Caps how many whole B tokens a single swap may sell, `None` removes the cap.
+    pub fn set_max_trade_b(&mut self, max_trade: Option<U128>) {
+        self.assert_owner();
+        self.max_trade_b = max_trade.map(|max_trade| max_trade.0);
+    }
+
+    /// Sets the smallest reserves in whole tokens a swap may leave behind, 0 removes a floor.
+    /// A floor keeps the integer pricing granular by stopping swaps from draining a reserve.
+    pub fn set_min_reserves(&mut self, min_reserve_a: U128, min_reserve_b: U128) {
+        self.assert_owner();
+        self.min_reserve_a = min_reserve_a.0;
+        self.min_reserve_b = min_reserve_b.0;
+    }
+
+    /// Sets the slippage in basis points deposit_a and deposit_b tolerate when called without a least output.
+    pub fn set_default_slippage(&mut self, default_slippage_bps: u16) {
+        self.assert_owner();
+        require!(
+            default_slippage_bps as u128 <= BPS_DENOMINATOR,
+            "default_slippage_bps must not exceed 10000"
+        );
+        self.default_slippage_bps = default_slippage_bps;
+    }
+
+    /// Sets how many decimals of each reserve `get_ratio` keeps, e.g. for a pair whose reserves are only
+    /// a few whole tokens. The ratio is recomputed right away. At most MAX_RATIO_PRECISION.
+    pub fn set_ratio_precision(&mut self, ratio_precision: u8) {
+        self.assert_owner();
+        require!(
+            ratio_precision <= MAX_RATIO_PRECISION,
+            "ratio_precision must not exceed 18"
+        );
+        self.ratio_precision = ratio_precision;
+        self.calc_ratio();
+    }
+
+    /// Sets the gas attached to the callbacks that make no further calls, e.g. the one updating the
+    /// tickers after a swap, for token contracts whose transfers leave less of it. The gas attached to
+    /// the calls scheduling them grows with it. Between GAS_FOR_CALLBACK and MAX_CALLBACK_GAS.
+    pub fn set_callback_gas(&mut self, callback_gas: Gas) {
+        self.assert_owner();
+        require!(
+            callback_gas >= GAS_FOR_CALLBACK && callback_gas <= MAX_CALLBACK_GAS,
+            "callback_gas must be between 10 and 100 Tgas"
+        );
+        self.callback_gas = callback_gas;
+    }
+
+    /// Guards the swaps with `oracle_account`: a swap only goes through while the spot price of A in B
+    /// is within `oracle_tolerance_bps` of the oracle price, `None` removes the guard.
+    pub fn set_oracle(&mut self, oracle_account: Option<AccountId>, oracle_tolerance_bps: u16) {
+        self.assert_owner();
+        require!(
+            oracle_tolerance_bps as u128 <= BPS_DENOMINATOR,
+            "oracle_tolerance_bps must not exceed 10000"
+        );
+        self.oracle_account = oracle_account;
+        self.oracle_tolerance_bps = oracle_tolerance_bps;
+    }
+
+    /// Marks `wrap_contract_id` as the wrapped NEAR side of the pool, so `deposit_near` can swap native
+    /// NEAR, `None` turns `deposit_near` off.
+    pub fn set_wrap_contract(&mut self, wrap_contract_id: Option<AccountId>) {
+        self.assert_owner();
+        if let Some(wrap_contract_id) = &wrap_contract_id {
+            require!(
+                *wrap_contract_id == self.a_contract_id || *wrap_contract_id == self.b_contract_id,
+                "token is not in the pool"
+            );
+        }
+        self.wrap_contract_id = wrap_contract_id;
+    }
+
+    /// Pauses the pool instead of updating the tickers when an update would move a reserve by more
+    /// than `breaker_bps` basis points, 0 turns the breaker off. A swap that would trip it is refunded
+    /// before its output is sent.
+    pub fn set_breaker(&mut self, breaker_bps: u16) {
+        self.assert_owner();
+        require!(
+            breaker_bps as u128 <= BPS_DENOMINATOR,
+            "breaker_bps must not exceed 10000"
+        );
+        self.breaker_bps = breaker_bps;
+    }
+
+    /// The liquidity provider fees of (A, B) not claimed yet by any provider, in minimum units.
+    pub fn get_fees(&self) -> (U128, U128) {
+        (self.a_fees.into(), self.b_fees.into())
+    }
+
+    /// The (A, B) fees in minimum units the shares of `account_id` earned and `claim_fees` would send.
+    pub fn get_claimable_fees(&self, account_id: AccountId) -> (U128, U128) {
+        let debt = self.settled_fees(&account_id);
+        (debt.a_pending.into(), debt.b_pending.into())
+    }
+
+    /// Sends the fees the shares of the caller earned since its last claim, for both tokens, without
+    /// touching the shares. Unlike `remove_liquidity` it leaves the position as it is.
+    pub fn claim_fees(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let mut debt = self.settled_fees(&account_id);
+        let (a_amount, b_amount) = (debt.a_pending, debt.b_pending);
+        require!(a_amount > 0 || b_amount > 0, "no fees to claim");
+        self.lock();
+        // taken out before the transfers so the same fees can't be claimed twice
+        debt.a_pending = 0;
+        debt.b_pending = 0;
+        self.reward_debts.insert(&account_id, &debt);
+        self.a_fees -= a_amount;
+        self.b_fees -= b_amount;
+        self.transfer_fees(&account_id, a_amount, b_amount).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(self.callback_gas)
+                .callback_claim_fees(account_id, a_amount, b_amount),
+        )
+    }
+
+    /// A transfer that failed leaves its fees with the contract, so the provider can claim them again.
+    pub fn callback_claim_fees(
+        &mut self,
+        account_id: AccountId,
+        a_amount: Balance,
+        b_amount: Balance,
+    ) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        let (a_amount, b_amount) = failed_fee_transfers(a_amount, b_amount);
+        if a_amount == 0 && b_amount == 0 {
+            return;
+        }
+        log!("Fee transfer to {} failed, fees are kept", account_id);
+        self.restore_fees(&account_id, a_amount, b_amount);
+    }
+
+    /// Sends the fees of `token` the owner's shares earned to the owner, as `claim_fees` does for both
+    /// tokens. Requires 1 yoctoNEAR.
+    #[payable]
+    pub fn collect_fees(&mut self, token: AccountId) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        let owner_id = self.owner_id.clone();
+        let mut debt = self.settled_fees(&owner_id);
+        let amount = if token == self.a_contract_id {
+            std::mem::take(&mut debt.a_pending)
+        } else if token == self.b_contract_id {
+            std::mem::take(&mut debt.b_pending)
+        } else {
+            env::panic_str("token is not in the pool")
+        };
+        require!(amount > 0, "no fees to collect");
+        self.lock();
+        // taken out before the transfer so the same fees can't be collected twice
+        self.reward_debts.insert(&owner_id, &debt);
+        if token == self.a_contract_id {
+            self.a_fees -= amount;
+        } else {
+            self.b_fees -= amount;
+        }
+        ext_token::ext(token.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(env::current_account_id(), owner_id.clone(), amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_collect_fees(token, owner_id, amount),
+            )
+    }
+
+    pub fn callback_collect_fees(
+        &mut self,
+        token: AccountId,
+        owner_id: AccountId,
+        amount: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        if transfer_result.is_err() {
+            log!("Fee transfer failed, fees are kept");
+            if token == self.a_contract_id {
+                self.restore_fees(&owner_id, amount, 0);
+            } else {
+                self.restore_fees(&owner_id, 0, amount);
+            }
+        }
+    }
+
+    /// Routes `protocol_fee_bps` of every swap fee, in basis points of the fee, to `fee_recipient`.
+    /// Being a part of the fee rather than of the trade, it never exceeds the fee whatever `fee_bps` is.
+    /// Requires 1 yoctoNEAR, as does `set_fee_recipient`.
+    #[payable]
+    pub fn set_protocol_fee(&mut self, protocol_fee_bps: u16, fee_recipient: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            protocol_fee_bps as u128 <= BPS_DENOMINATOR,
+            "protocol_fee_bps must not exceed 10000"
+        );
+        self.protocol_fee_bps = protocol_fee_bps;
+        self.change_fee_recipient(fee_recipient);
+    }
+
+    /// Sends the protocol fees collected from now on to `new_recipient`, e.g. a new treasury.
+    #[payable]
+    pub fn set_fee_recipient(&mut self, new_recipient: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.change_fee_recipient(new_recipient);
+    }
+
+    fn change_fee_recipient(&mut self, new_recipient: AccountId) {
+        // the pool can't transfer_from itself to itself
+        require!(
+            new_recipient != env::current_account_id(),
+            "the pool can't be the fee recipient"
+        );
+        if new_recipient != self.fee_recipient {
+            events::FeeRecipientChanged {
+                old_recipient: &self.fee_recipient,
+                new_recipient: &new_recipient,
+            }
+            .emit();
+            self.fee_recipient = new_recipient;
+        }
+    }
+
+    /// Protocol fees accumulated so far for (A, B), in minimum units.
+    pub fn get_protocol_fees(&self) -> (U128, U128) {
+        (self.a_protocol_fees.into(), self.b_protocol_fees.into())
+    }
+
+    /// Sends the accumulated protocol fees of `token` to the fee recipient.
+    pub fn collect_protocol_fees(&mut self, token: AccountId) -> Promise {
+        // taken out before the transfer so the same fees can't be collected twice
+        let amount = if token == self.a_contract_id {
+            std::mem::take(&mut self.a_protocol_fees)
+        } else if token == self.b_contract_id {
+            std::mem::take(&mut self.b_protocol_fees)
+        } else {
+            env::panic_str("token is not in the pool")
+        };
+        require!(amount > 0, "no fees to collect");
+        self.lock();
+        ext_token::ext(token.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(
+                env::current_account_id(),
+                self.fee_recipient.clone(),
+                amount,
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_collect_protocol_fees(token, amount),
+            )
+    }
+
+    /// A transfer that failed leaves its fees with the contract, so they can be collected again.
+    pub fn callback_collect_protocol_fees(
+        &mut self,
+        token: AccountId,
+        amount: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        if transfer_result.is_ok() {
+            return;
+        }
+        log!("Protocol fee transfer failed, fees are kept");
+        if token == self.a_contract_id {
+            self.a_protocol_fees += amount;
+        } else {
+            self.b_protocol_fees += amount;
+        }
+    }
+
+    /// Routes `referral_bps` of the swap fee left after the protocol fee, in basis points, to the referrer
+    /// a swap names. Without a referrer that part stays with the liquidity providers.
+    pub fn set_referral_fee(&mut self, referral_bps: u16) {
+        self.assert_owner();
+        require!(
+            referral_bps as u128 <= BPS_DENOMINATOR,
+            "referral_bps must not exceed 10000"
+        );
+        self.referral_bps = referral_bps;
+    }
+
+    /// Referral fees `account_id` can claim for (A, B), in minimum units.
+    pub fn get_referral_fees(&self, account_id: AccountId) -> (U128, U128) {
+        let (a_fees, b_fees) = self.referral_fees.get(&account_id).unwrap_or((0, 0));
+        (a_fees.into(), b_fees.into())
+    }
+
+    /// Sends the referral fees of the caller for both tokens to the caller.
+    pub fn claim_referral_fees(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let (a_amount, b_amount) = self
+            .referral_fees
+            .remove(&account_id)
+            .expect("no referral fees to claim");
+        self.lock();
+        // taken out before the transfers so the same fees can't be claimed twice
+        self.a_referral_fees -= a_amount;
+        self.b_referral_fees -= b_amount;
+        self.transfer_fees(&account_id, a_amount, b_amount).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(self.callback_gas)
+                .callback_claim_referral_fees(account_id, a_amount, b_amount),
+        )
+    }
+
+    /// A transfer that failed leaves its fees with the contract, so the referrer can claim them again.
+    pub fn callback_claim_referral_fees(
+        &mut self,
+        account_id: AccountId,
+        a_amount: Balance,
+        b_amount: Balance,
+    ) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        let (a_amount, b_amount) = failed_fee_transfers(a_amount, b_amount);
+        if a_amount == 0 && b_amount == 0 {
+            return;
+        }
+        log!(
+            "Referral fee transfer to {} failed, fees are kept",
+            account_id
+        );
+        let (a_fees, b_fees) = self.referral_fees.get(&account_id).unwrap_or((0, 0));
+        self.referral_fees
+            .insert(&account_id, &(a_fees + a_amount, b_fees + b_amount));
+        self.a_referral_fees += a_amount;
+        self.b_referral_fees += b_amount;
+    }
+
+    /// Sends `amount` of a token that is not part of the pool, sent here by mistake, to `to`.
+    /// Any NEP-141 token can be rescued, so this uses the standard `ft_transfer` rather than `transfer_from`.
+    /// Requires 1 yoctoNEAR.
+    #[payable]
+    pub fn rescue_token(&mut self, token: AccountId, amount: U128, to: AccountId) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            token != self.a_contract_id && token != self.b_contract_id,
+            "pool tokens can't be rescued"
+        );
+        ext_token::ext(token)
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(to, amount, None)
+    }
+
+    /// Provides liquidity from the caller, amounts are in minimum units.
+    /// The first deposit into an empty pool mints sqrt(a * b) shares, of which MINIMUM_LIQUIDITY are locked forever,
+    /// later ones pull both tokens in the current reserve proportion, up to `a_amount` and `b_amount`,
+    /// and mint shares accordingly.
+    /// Like a swap it holds the lock until the tokens arrived, as the swap callbacks set the tickers they
+    /// priced against.
+    pub fn add_liquidity(&mut self, a_amount: U128, b_amount: U128) -> Promise {
+        self.check_not_paused().or_panic();
+        let account_id = env::predecessor_account_id();
+        require!(
+            self.shares.contains_key(&account_id),
+            "account is not registered, call storage_deposit first"
+        );
+        let (shares, a_amount, b_amount) = self.calc_add_liquidity(a_amount.0, b_amount.0);
+        require!(
+            shares > 0,
+            if self.total_shares == 0 {
+                "the first deposit must mint more than MINIMUM_LIQUIDITY shares"
+            } else {
+                "liquidity amount is too small"
+            }
+        );
+        self.lock();
+        ext_token::ext(self.a_contract_id.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(account_id.clone(), env::current_account_id(), a_amount)
+            .and(
+                ext_token::ext(self.b_contract_id.clone())
+                    .with_static_gas(GAS_FOR_TRANSFER_FROM)
+                    .transfer_from(account_id.clone(), env::current_account_id(), b_amount),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_add_liquidity(account_id, shares, a_amount, b_amount),
+            )
+    }
+
+    /// Mints the shares once both tokens arrived, otherwise returns the token that did arrive.
+    pub fn callback_add_liquidity(
+        &mut self,
+        account_id: AccountId,
+        shares: Balance,
+        a_amount: Balance,
+        b_amount: Balance,
+        #[callback_result] a_result: Result<(), PromiseError>,
+        #[callback_result] b_result: Result<(), PromiseError>,
+    ) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        match (a_result, b_result) {
+            (Ok(()), Ok(())) => {
+                let balance = self.shares.get(&account_id).unwrap_or(0);
+                self.set_shares(&account_id, balance + shares);
+                if self.total_shares == 0 {
+                    // the locked part of the first deposit
+                    self.total_shares = MINIMUM_LIQUIDITY;
+                }
+                self.total_shares += shares;
+                self.a_ticker += a_amount;
+                self.b_ticker += b_amount;
+                self.calc_ratio();
+            }
+            (Ok(()), Err(_)) => {
+                log!("B transfer failed, returning A to {}", account_id);
+                ext_token::ext(self.a_contract_id.clone())
+                    .with_static_gas(GAS_FOR_TRANSFER_FROM)
+                    .transfer_from(env::current_account_id(), account_id, a_amount);
+            }
+            (Err(_), Ok(())) => {
+                log!("A transfer failed, returning B to {}", account_id);
+                ext_token::ext(self.b_contract_id.clone())
+                    .with_static_gas(GAS_FOR_TRANSFER_FROM)
+                    .transfer_from(env::current_account_id(), account_id, b_amount);
+            }
+            (Err(_), Err(_)) => log!("Liquidity transfers failed"),
+        }
+    }
+
+    /// Provides liquidity from `amount_in` minimum units of `token_in` alone: the pool pulls it, swaps the
+    /// part of it that leaves the rest and the output in the proportion of the reserves, and mints the
+    /// caller shares for both, as swapping and then calling `add_liquidity` would. The swap pays the fee.
+    /// Fewer than `min_shares` shares refund the input. The returned promise resolves to the shares minted.
+    pub fn zap_in(
+        &mut self,
+        token_in: AccountId,
+        amount_in: U128,
+        min_shares: Option<U128>,
+    ) -> Promise {
+        check_amount(amount_in.0).or_panic();
+        self.check_not_paused().or_panic();
+        let account_id = env::predecessor_account_id();
+        require!(
+            self.shares.contains_key(&account_id),
+            "account is not registered, call storage_deposit first"
+        );
+        if token_in == self.a_contract_id {
+            require!(!self.paused_a_to_b, "swaps from A to B are paused");
+        } else if token_in == self.b_contract_id {
+            require!(!self.paused_b_to_a, "swaps from B to A are paused");
+        } else {
+            env::panic_str("token_in is not in the pool");
+        }
+        self.assert_allowed(&account_id);
+        // like deposit_near, the swap has no oracle step, so it fails closed while the guard is set
+        require!(
+            self.oracle_account.is_none(),
+            "zap_in is unavailable while the oracle guard is set"
+        );
+        require!(
+            self.registered_a && self.registered_b,
+            "token registration not complete"
+        );
+        self.check_initialized().or_panic();
+        let (swap_in, _, _, shares) = self.calc_zap(&token_in, amount_in.0);
+        self.check_max_trade(&token_in, swap_in);
+        require!(shares > 0, "liquidity amount is too small");
+        let min_shares = min_shares.map_or(0, |min_shares| min_shares.0);
+        check_slippage(shares, min_shares).or_panic();
+        // the zap is priced once the input has arrived, no swap may move the reserves meanwhile
+        self.lock();
+        ext_token::ext(token_in.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(account_id.clone(), env::current_account_id(), amount_in.0)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas_for_zap())
+                    .callback_zap_in(account_id, token_in, amount_in.0, min_shares),
+            )
+    }
+
+    /// Swaps and mints the shares once the input arrived, or refunds it if the shares fell short.
+    pub fn callback_zap_in(
+        &mut self,
+        account_id: AccountId,
+        token_in: AccountId,
+        amount_in: Balance,
+        min_shares: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> U128 {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        if transfer_result.is_err() {
+            log!("Zap input transfer failed");
+            return U128(0);
+        }
+        let (swap_in, fee, amount_out, shares) = self.calc_zap(&token_in, amount_in);
+        if shares == 0 || shares < min_shares {
+            log!("Zap would mint {} shares, refunding {}", shares, account_id);
+            ext_token::ext(token_in)
+                .with_static_gas(GAS_FOR_TRANSFER_FROM)
+                .transfer_from(env::current_account_id(), account_id, amount_in);
+            return U128(0);
+        }
+        self.credit_fee(&token_in, fee, None);
+        let token_out = if token_in == self.a_contract_id {
+            self.volume_a_in += swap_in;
+            self.b_contract_id.clone()
+        } else {
+            self.volume_b_in += swap_in;
+            self.a_contract_id.clone()
+        };
+        events::Swap {
+            account_id: &account_id,
+            token_in: &token_in,
+            amount_in: &U128(swap_in),
+            token_out: &token_out,
+            amount_out: &U128(amount_out),
+        }
+        .emit();
+        self.record_trade(TradeRecord {
+            timestamp: env::block_timestamp(),
+            token_in: token_in.clone(),
+            amount_in: swap_in.into(),
+            amount_out: amount_out.into(),
+            account_id: account_id.clone(),
+        });
+        let balance = self.shares.get(&account_id).unwrap_or(0);
+        self.set_shares(&account_id, balance + shares);
+        self.total_shares += shares;
+        // the old reserves priced the pool until now
+        self.update_price_cumulative();
+        // the output of the swap is added back right away, so only the input reserve grows, by all of the
+        // input but the fee, and the rounding of the shares stays in the reserves
+        if token_in == self.a_contract_id {
+            self.a_ticker += amount_in - fee;
+        } else {
+            self.b_ticker += amount_in - fee;
+        }
+        self.last_update_block = env::block_height();
+        self.calc_ratio();
+        events::Sync {
+            a_ticker: self.a_ticker.into(),
+            b_ticker: self.b_ticker.into(),
+        }
+        .emit();
+        shares.into()
+    }
+
+    /// Burns `shares` of the caller and sends back the proportional part of both reserves.
+    /// It holds the lock until the transfers are done, so no swap is priced against reserves that a
+    /// failed transfer still puts back.
+    pub fn remove_liquidity(&mut self, shares: U128) -> Promise {
+        self.check_not_paused().or_panic();
+        self.lock();
+        let account_id = env::predecessor_account_id();
+        let balance = self.shares.get(&account_id).unwrap_or(0);
+        require!(shares.0 > 0 && shares.0 <= balance, "not enough shares");
+        let (a_amount, b_amount) = self.calc_remove_liquidity(shares.0);
+        // burn before the transfers so the same shares can't be withdrawn twice,
+        // the entry stays since the account paid for its storage
+        self.set_shares(&account_id, balance - shares.0);
+        self.total_shares -= shares.0;
+        self.a_ticker -= a_amount;
+        self.b_ticker -= b_amount;
+        self.calc_ratio();
+        ext_token::ext(self.a_contract_id.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(env::current_account_id(), account_id.clone(), a_amount)
+            .and(
+                ext_token::ext(self.b_contract_id.clone())
+                    .with_static_gas(GAS_FOR_TRANSFER_FROM)
+                    .transfer_from(env::current_account_id(), account_id.clone(), b_amount),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_remove_liquidity(account_id, a_amount, b_amount),
+            )
+    }
+
+    /// A transfer that failed leaves its tokens with the contract, so they go back into the reserves.
+    pub fn callback_remove_liquidity(
+        &mut self,
+        account_id: AccountId,
+        a_amount: Balance,
+        b_amount: Balance,
+        #[callback_result] a_result: Result<(), PromiseError>,
+        #[callback_result] b_result: Result<(), PromiseError>,
+    ) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        if a_result.is_err() {
+            log!("Withdrawal of {} A to {} failed", a_amount, account_id);
+            self.a_ticker += a_amount;
+        }
+        if b_result.is_err() {
+            log!("Withdrawal of {} B to {} failed", b_amount, account_id);
+            self.b_ticker += b_amount;
+        }
+        self.calc_ratio();
+    }
+
+    pub fn get_share_balance(&self, account_id: AccountId) -> U128 {
+        self.shares.get(&account_id).unwrap_or(0).into()
+    }
+
+    pub fn get_total_shares(&self) -> U128 {
+        self.total_shares.into()
+    }
+
+    /// The (A, B) amounts in minimum units that the shares of `account_id` could be withdrawn for now.
+    pub fn get_share_value(&self, account_id: AccountId) -> (U128, U128) {
+        let shares = self.shares.get(&account_id).unwrap_or(0);
+        if shares == 0 {
+            return (U128(0), U128(0));
+        }
+        (
+            mul_div(shares, self.a_ticker, self.total_shares).into(),
+            mul_div(shares, self.b_ticker, self.total_shares).into(),
+        )
+    }
+
+    /// The shares `add_liquidity(a_amount, b_amount)` would mint now, without the locked MINIMUM_LIQUIDITY
+    /// of a first deposit, 0 if the deposit is too small.
+    pub fn quote_add_liquidity(&self, a_amount: U128, b_amount: U128) -> U128 {
+        self.calc_add_liquidity(a_amount.0, b_amount.0).0.into()
+    }
+
+    /// The (A, B) amounts in minimum units `remove_liquidity(shares)` would send now, rounded down.
+    pub fn quote_remove_liquidity(&self, shares: U128) -> (U128, U128) {
+        require!(shares.0 <= self.total_shares, "not enough shares");
+        if shares.0 == 0 {
+            return (U128(0), U128(0));
+        }
+        let (a_amount, b_amount) = self.calc_remove_liquidity(shares.0);
+        (a_amount.into(), b_amount.into())
+    }
+
+    /// The proportional part of both reserves that burning `shares` withdraws.
+    fn calc_remove_liquidity(&self, shares: Balance) -> (Balance, Balance) {
+        (
+            mul_div(shares, self.a_ticker, self.total_shares),
+            mul_div(shares, self.b_ticker, self.total_shares),
+        )
+    }
+
+    /// The shares minted for a deposit of up to `a_amount` and `b_amount`, and the amounts pulled for them.
+    fn calc_add_liquidity(
+        &self,
+        a_amount: Balance,
+        b_amount: Balance,
+    ) -> (Balance, Balance, Balance) {
+        if self.total_shares == 0 {
+            let shares = (U256::from(a_amount) * U256::from(b_amount))
+                .integer_sqrt()
+                .as_u128();
+            (shares.saturating_sub(MINIMUM_LIQUIDITY), a_amount, b_amount)
+        } else {
+            let shares = std::cmp::min(
+                mul_div(self.total_shares, a_amount, self.a_ticker),
+                mul_div(self.total_shares, b_amount, self.b_ticker),
+            );
+            (
+                shares,
+                mul_div_ceil(shares, self.a_ticker, self.total_shares),
+                mul_div_ceil(shares, self.b_ticker, self.total_shares),
+            )
+        }
+    }
+
+    fn measure_account_storage_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+        self.shares.insert(&tmp_account_id, &0);
+        self.reward_debts
+            .insert(&tmp_account_id, &RewardDebt::default());
+        self.account_storage_usage = env::storage_usage() - initial_storage_usage;
+        self.shares.remove(&tmp_account_id);
+        self.reward_debts.remove(&tmp_account_id);
+    }
+
+    fn register_amm(&self, token: AccountId, amount: Balance) {
+        ext_token::ext(token.clone())
+            .with_static_gas(GAS_FOR_REGISTER_AMM)
+            .register_amm(self.owner_id.clone(), amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_register_amm(token),
+            );
+    }
+
+    fn fetch_metadata(&self) {
+        ext_token::ext(self.a_contract_id.clone())
+            .with_static_gas(GAS_FOR_VIEW)
+            .get_info()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_get_info(self.a_contract_id.clone()),
+            );
+        ext_token::ext(self.b_contract_id.clone())
+            .with_static_gas(GAS_FOR_VIEW)
+            .get_info()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_get_info(self.b_contract_id.clone()),
+            );
+    }
+
+    /// The reward debt of `account_id` with the fees its shares earned since the last settlement moved to
+    /// its pending fees. The debt is only stored by the callers that change the account's fees or shares.
+    pub(crate) fn settled_fees(&self, account_id: &AccountId) -> RewardDebt {
+        let mut debt = self.reward_debts.get(account_id).unwrap_or_default();
+        let shares = U256::from(self.shares.get(account_id).unwrap_or(0));
+        let precision = U256::from(FEE_PER_SHARE_PRECISION);
+        let a_earned = (shares * self.a_fee_per_share - debt.a_debt) / precision;
+        let b_earned = (shares * self.b_fee_per_share - debt.b_debt) / precision;
+        debt.a_pending += a_earned.as_u128();
+        debt.b_pending += b_earned.as_u128();
+        debt.a_debt += a_earned * precision;
+        debt.b_debt += b_earned * precision;
+        debt
+    }
+
+    /// Settles the fees of `account_id` and sets its shares to `shares`, from then on they earn fees
+    /// at the new size.
+    fn set_shares(&mut self, account_id: &AccountId, shares: Balance) {
+        let mut debt = self.settled_fees(account_id);
+        debt.a_debt = U256::from(shares) * self.a_fee_per_share;
+        debt.b_debt = U256::from(shares) * self.b_fee_per_share;
+        self.reward_debts.insert(account_id, &debt);
+        self.shares.insert(account_id, &shares);
+    }
+
+    /// Gives fees whose transfer failed back to the pending fees of `account_id`.
+    fn restore_fees(&mut self, account_id: &AccountId, a_amount: Balance, b_amount: Balance) {
+        let mut debt = self.settled_fees(account_id);
+        debt.a_pending += a_amount;
+        debt.b_pending += b_amount;
+        self.reward_debts.insert(account_id, &debt);
+        self.a_fees += a_amount;
+        self.b_fees += b_amount;
+    }
+
+    /// Transfers the positive ones of `a_amount` and `b_amount` to `account_id`, joined A first.
+    fn transfer_fees(
+        &self,
+        account_id: &AccountId,
+        a_amount: Balance,
+        b_amount: Balance,
+    ) -> Promise {
+        let transfer = |token: &AccountId, amount: Balance| {
+            Some(amount).filter(|amount| *amount > 0).map(|amount| {
+                ext_token::ext(token.clone())
+                    .with_static_gas(GAS_FOR_TRANSFER_FROM)
+                    .transfer_from(env::current_account_id(), account_id.clone(), amount)
+            })
+        };
+        match (
+            transfer(&self.a_contract_id, a_amount),
+            transfer(&self.b_contract_id, b_amount),
+        ) {
+            (Some(a_transfer), Some(b_transfer)) => a_transfer.and(b_transfer),
+            (Some(transfer), None) | (None, Some(transfer)) => transfer,
+            // the callers only claim positive fees
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// The zap of `amount_in` minimum units of `token_in`, see `zap_in`, as (swap input, fee, swap output,
+    /// shares). With r = 1 - fee, the swap input s solves r²s² + x(1 + r)s = xa for the input reserve x,
+    /// which makes what is left of the input, a - s, over the input reserve after the swap, x + rs,
+    /// equal to the output over the output reserve after the swap.
+    fn calc_zap(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+    ) -> (Balance, Balance, Balance, Balance) {
+        let x = U256::from(if *token_in == self.a_contract_id {
+            self.a_ticker
+        } else {
+            self.b_ticker
+        });
+        // r in basis points
+        let denominator = U256::from(BPS_DENOMINATOR);
+        let kept = U256::from(BPS_DENOMINATOR - self.active_fee_bps() as u128);
+        let half_b = x * (denominator + kept);
+        let discriminant =
+            half_b * half_b + U256::from(4) * kept * kept * x * U256::from(amount_in);
+        let swap_in = ((discriminant.integer_sqrt() - half_b) * denominator
+            / (U256::from(2) * kept * kept))
+            .as_u128()
+            .min(amount_in);
+        match self.try_calc_swap_units(token_in, swap_in) {
+            Ok((_, fee, x_after, y_after, amount_out)) => {
+                let shares = std::cmp::min(
+                    mul_div(self.total_shares, amount_in - swap_in, x_after),
+                    mul_div(self.total_shares, amount_out, y_after),
+                );
+                (swap_in, fee, amount_out, shares)
+            }
+            Err(_) => (swap_in, 0, 0, 0),
+        }
+    }
+
+    /// The token and the amount in minimum units, fee included, that `swap_to_price` sells to move the
+    /// spot price to `num / den` whole B per whole A, or None if it is there already.
+    /// Against K the input reserve has to reach sqrt(K * x / y), with y / x the target in minimum units.
+    fn calc_price_target_input(&self, num: Balance, den: Balance) -> Option<(AccountId, Balance)> {
+        // the target in minimum units of B per minimum unit of A, as y / x
+        let (a_decimals, b_decimals) = self.decimals();
+        let (y, x) = if b_decimals >= a_decimals {
+            let scale = U256::exp10((b_decimals - a_decimals) as usize);
+            (U256::from(num) * scale, U256::from(den))
+        } else {
+            let scale = U256::exp10((a_decimals - b_decimals) as usize);
+            (U256::from(num), U256::from(den) * scale)
+        };
+        let (a_ticker, b_ticker) = (U256::from(self.a_ticker), U256::from(self.b_ticker));
+        // a lower price sells A, a higher one B
+        let (token_in, reserve, num, den) = if y * a_ticker < x * b_ticker {
+            (self.a_contract_id.clone(), self.a_ticker, x, y)
+        } else {
+            (self.b_contract_id.clone(), self.b_ticker, y, x)
+        };
+        let square = (self.k / den)
+            .checked_mul(num)
+            .and_then(|square| square.checked_add(self.k % den * num / den))
+            .expect("target price overflow");
+        let reserve_after: Balance = square.integer_sqrt().try_into().expect("ticker overflow");
+        let amount_in = reserve_after.saturating_sub(reserve);
+        if amount_in == 0 {
+            return None;
+        }
+        let fee_keep = BPS_DENOMINATOR - self.active_fee_bps() as u128;
+        Some((token_in, mul_div_ceil(amount_in, BPS_DENOMINATOR, fee_keep)))
+    }
+
+    /// callback_zap_in refunds the input if the zap falls short, which makes no further calls
+    fn gas_for_zap(&self) -> Gas {
+        Gas(GAS_FOR_TRANSFER_FROM.0 + self.callback_gas.0 + GAS_FOR_RECEIPT.0)
+    }
+
+    /// callback_swap_output refunds the input if the output transfer failed, with callback_swap_refund after it
+    fn gas_for_swap_refund(&self) -> Gas {
+        Gas(GAS_FOR_TRANSFER_FROM.0 + self.callback_gas.0 + 2 * GAS_FOR_RECEIPT.0)
+    }
+
+    /// callback_ft_deposit sends the output and schedules callback_swap_output
+    fn gas_for_swap_output(&self) -> Gas {
+        Gas(GAS_FOR_TRANSFER_FROM.0 + self.gas_for_swap_refund().0 + 2 * GAS_FOR_RECEIPT.0)
+    }
+
+    /// callback_ft_deposit_balance pulls the input, reads the balance and schedules callback_ft_deposit
+    fn gas_for_pull_swap_input(&self) -> Gas {
+        Gas(GAS_FOR_TRANSFER_FROM.0
+            + GAS_FOR_VIEW.0
+            + self.gas_for_swap_output().0
+            + 3 * GAS_FOR_RECEIPT.0)
+    }
+
+    /// callback_oracle_swap checks the oracle price, reads the balance and schedules callback_ft_deposit_balance
+    fn gas_for_oracle_swap(&self) -> Gas {
+        Gas(GAS_FOR_VIEW.0 + self.gas_for_pull_swap_input().0 + 2 * GAS_FOR_RECEIPT.0)
+    }
+
+    /// callback_flash_swap_lend lends, calls the receiver, reads the balance and schedules callback_flash_swap
+    fn gas_for_flash_loan(&self) -> Gas {
+        Gas(GAS_FOR_TRANSFER_FROM.0
+            + GAS_FOR_ON_FLASH_SWAP.0
+            + GAS_FOR_VIEW.0
+            + self.callback_gas.0
+            + 4 * GAS_FOR_RECEIPT.0)
+    }
+
+    fn lock(&mut self) {
+        require!(!self.locked, "reentrant call");
+        self.locked = true;
+        self.locked_at = env::block_height();
+    }
+
+    fn assert_allowed(&self, account_id: &AccountId) {
+        if let Some(allowlist) = &self.allowlist {
+            require!(
+                allowlist.contains(account_id),
+                "account is not on the allowlist"
+            );
+        }
+    }
+
+    fn assert_owner(&self) {
+        require!(
+            env::predecessor_account_id() == self.owner_id,
+            "only the owner can call this method"
+        );
+    }
+
+    /// The owner may only take reserves out at will while no other provider has a part of them.
+    fn assert_sole_provider(&self) {
+        require!(
+            self.total_shares == self.shares.get(&self.owner_id).unwrap_or(0),
+            "liquidity providers still hold shares"
+        );
+    }
+
+    /// Decimals of (A, B), panics while they haven't arrived yet instead of guessing them.
+    fn decimals(&self) -> (u8, u8) {
+        require!(
+            self.a_contract_decimals.is_some() && self.b_contract_decimals.is_some(),
+            "token decimals not loaded"
+        );
+        (
+            self.a_contract_decimals.unwrap_or_default(),
+            self.b_contract_decimals.unwrap_or_default(),
+        )
+    }
+
+    /// The fee at the current block time, following the fee ramp while there is one.
+    fn active_fee_bps(&self) -> u16 {
+        let now = env::block_timestamp();
+        if self.fee_ramp_end == 0 || now >= self.fee_ramp_end {
+            return self.fee_bps;
+        }
+        if now <= self.fee_ramp_start {
+            return self.fee_start_bps;
+        }
+        let (elapsed, duration) = (
+            (now - self.fee_ramp_start) as u128,
+            (self.fee_ramp_end - self.fee_ramp_start) as u128,
+        );
+        let (start, end) = (self.fee_start_bps as u128, self.fee_end_bps as u128);
+        let fee = if end >= start {
+            start + mul_div(end - start, elapsed, duration)
+        } else {
+            start - mul_div(start - end, elapsed, duration)
+        };
+        fee as u16
+    }
+
+    fn display_ratio(&self) -> u128 {
+        self.ratio.try_into().unwrap_or(u128::MAX)
+    }
+
+    fn calc_ratio(&mut self) {
+        // the ratio is in whole tokens, or ratio_precision decimals of them, so it stays 0 until both
+        // decimals are known, and is 0 for a reserve below the precision, which only affects the
+        // display since swaps use K
+        if let (Some(a_decimals), Some(b_decimals)) =
+            (self.a_contract_decimals, self.b_contract_decimals)
+        {
+            let scale = |ticker: Balance, decimals: u8| {
+                let ticker = U256::from(ticker);
+                if self.ratio_precision <= decimals {
+                    ticker / U256::exp10((decimals - self.ratio_precision) as usize)
+                } else {
+                    ticker * U256::exp10((self.ratio_precision - decimals) as usize)
+                }
+            };
+            self.ratio = scale(self.a_ticker, a_decimals) * scale(self.b_ticker, b_decimals);
+        }
+        //X * Y = K , K is kept exact so that truncated token counts can't erode it
+        self.k = U256::from(self.a_ticker) * U256::from(self.b_ticker);
+    }
+
+    /// Computes a swap of `amount` whole tokens of `token_in` against K, see `calc_swap_units`.
+    fn calc_swap(
+        &self,
+        token_in: &AccountId,
+        amount: Balance,
+    ) -> (Balance, Balance, Balance, Balance, Balance) {
+        self.calc_swap_units(token_in, self.to_units(token_in, amount))
+    }
+
+    /// `amount` whole tokens of `token` in minimum units
+    fn to_units(&self, token: &AccountId, amount: Balance) -> Balance {
+        let (a_decimals, b_decimals) = self.decimals();
+        let decimals = if *token == self.a_contract_id {
+            a_decimals
+        } else {
+            b_decimals
+        };
+        amount
+            .checked_mul(10_u128.pow(decimals as u32))
+            .expect("amount overflow")
+    }
+
+    /// Quotes a swap of `amount` whole tokens of `token_in` like `calc_swap`, for the output amount only.
+    fn quote_amount_out(
+        &self,
+        token_in: &AccountId,
+        amount: Balance,
+    ) -> Result<Balance, PoolError> {
+        self.check_initialized()?;
+        Ok(self
+            .try_calc_swap_units(token_in, self.to_units(token_in, amount))?
+            .4)
+    }
+
+    /// Computes a swap of `amount_in` minimum units of `token_in` against K.
+    /// The fee is taken off the input before pricing and is not added to the input ticker.
+    /// The output ticker is rounded up, so the reserves after the swap never hold less than K.
+    /// Returns (input amount, fee, input ticker after, output ticker after, output amount) in minimum units.
+    fn calc_swap_units(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+    ) -> (Balance, Balance, Balance, Balance, Balance) {
+        let insufficient = if *token_in == self.a_contract_id {
+            "insufficient B liquidity for this trade"
+        } else {
+            "insufficient A liquidity for this trade"
+        };
+        self.try_calc_swap_units(token_in, amount_in)
+            .unwrap_or_else(|_| panic!("{}", insufficient))
+    }
+
+    /// `calc_swap_units` returning `InsufficientLiquidity` where the output reserve can't cover the trade.
+    fn try_calc_swap_units(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+    ) -> Result<(Balance, Balance, Balance, Balance, Balance), PoolError> {
+        let (x, y) = if *token_in == self.a_contract_id {
+            (self.a_ticker, self.b_ticker)
+        } else {
+            (self.b_ticker, self.a_ticker)
+        };
+        let (fee, x_after, y_after, amount_out) =
+            swap_units(x, y, self.k, self.active_fee_bps(), amount_in)?;
+        Ok((amount_in, fee, x_after, y_after, amount_out))
+    }
+
+    fn check_not_paused(&self) -> Result<(), PoolError> {
+        if self.paused {
+            return Err(PoolError::Paused);
+        }
+        Ok(())
+    }
+
+    /// The pool prices against K in minimum units, the finest scale there is, so reserves of less than
+    /// one whole token, for which the ratio in whole tokens is 0, can still be traded.
+    /// A pool with an empty reserve, e.g. after `close_pool`, can't price anything until it is seeded
+    /// again with `add_liquidity` or the owner deposits.
+    fn check_initialized(&self) -> Result<(), PoolError> {
+        let decimals_known =
+            self.a_contract_decimals.is_some() && self.b_contract_decimals.is_some();
+        if !decimals_known {
+            return Err(PoolError::NotInitialized);
+        }
+        if self.a_ticker == 0 || self.b_ticker == 0 {
+            return Err(PoolError::EmptyPool);
+        }
+        if self.k.is_zero() {
+            return Err(PoolError::NotInitialized);
+        }
+        Ok(())
+    }
+
+    /// Quotes the least amount of the other token in whole tokens, as `deposit_a` and `deposit_b` take it,
+    /// that swaps for at least `amount_out` minimum units of `token_out`, fee included and rounded up.
+    /// The inverse of `get_amount_out`, which takes and returns the same units.
+    pub fn get_amount_in(&self, token_out: AccountId, amount_out: U128) -> U128 {
+        let (a_decimals, b_decimals) = self.decimals();
+        let (x, y, decimals_in) = if token_out == self.b_contract_id {
+            (self.a_ticker, self.b_ticker, a_decimals)
+        } else if token_out == self.a_contract_id {
+            (self.b_ticker, self.a_ticker, b_decimals)
+        } else {
+            env::panic_str("token_out is not in the pool")
+        };
+        require!(amount_out.0 < y, "amount_out must be less than the reserve");
+        // the output ticker may not drop below y - amount_out, so x + input has to reach K / (y - amount_out)
+        let y_after = U256::from(y - amount_out.0);
+        let x_after: u128 = ((self.k + y_after - 1) / y_after)
+            .try_into()
+            .expect("ticker overflow");
+        let amount_in = x_after.saturating_sub(x);
+        let fee_keep = BPS_DENOMINATOR - self.active_fee_bps() as u128;
+        mul_div_ceil(amount_in, BPS_DENOMINATOR, fee_keep)
+            .div_ceil(10_u128.pow(decimals_in as u32))
+            .into()
+    }
+
+    /// The least output in minimum units a swap of `amount` whole tokens of `token_in` accepts without an
+    /// explicit one, the expected output less `default_slippage_bps`.
+    fn default_min_out(&self, token_in: &AccountId, amount: Balance) -> Balance {
+        let expected = self.quote_amount_out(token_in, amount).or_panic();
+        mul_div(
+            expected,
+            BPS_DENOMINATOR - self.default_slippage_bps as u128,
+            BPS_DENOMINATOR,
+        )
+    }
+
+    /// Rejects a swap selling more than the owner's cap for `token_in`, `amount_in` is in minimum units.
+    fn check_max_trade(&self, token_in: &AccountId, amount_in: Balance) {
+        let (max_trade, decimals) = if *token_in == self.a_contract_id {
+            (self.max_trade_a, self.decimals().0)
+        } else {
+            (self.max_trade_b, self.decimals().1)
+        };
+        if let Some(max_trade) = max_trade {
+            require!(
+                amount_in <= max_trade.saturating_mul(10_u128.pow(decimals as u32)),
+                "amount exceeds the max trade size"
+            );
+        }
+    }
+
+    /// Rejects a swap leaving either reserve below the owner's floor, the tickers are in minimum units.
+    fn check_min_reserves(&self, a_ticker_after: Balance, b_ticker_after: Balance) {
+        let (a_decimals, b_decimals) = self.decimals();
+        require!(
+            a_ticker_after
+                >= self
+                    .min_reserve_a
+                    .saturating_mul(10_u128.pow(a_decimals as u32))
+                && b_ticker_after
+                    >= self
+                        .min_reserve_b
+                        .saturating_mul(10_u128.pow(b_decimals as u32)),
+            "trade would take a reserve below its floor"
+        );
+    }
+
+    /// Rejects a swap whose output is more than `max_impact_bps` below what `amount_in` (after the fee)
+    /// would buy at the spot price of the current reserves.
+    fn check_price_impact(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        amount_out: Balance,
+        max_impact_bps: u16,
+    ) {
+        require!(
+            max_impact_bps as u128 <= BPS_DENOMINATOR,
+            "max_impact_bps must not exceed 10000"
+        );
+        let (x, y) = if *token_in == self.a_contract_id {
+            (self.a_ticker, self.b_ticker)
+        } else {
+            (self.b_ticker, self.a_ticker)
+        };
+        let spot_out = mul_div(amount_in, y, x);
+        require!(
+            U256::from(amount_out) * U256::from(BPS_DENOMINATOR)
+                >= U256::from(spot_out) * U256::from(BPS_DENOMINATOR - max_impact_bps as u128),
+            "price impact too high"
+        );
+    }
+
+    /// The largest part of an order of `amount` whole tokens of `token_in` that gets at least the price
+    /// of `min_out` for `amount`, and the minimum output scaled down to that part.
+    fn calc_partial_fill(
+        &self,
+        token_in: &AccountId,
+        amount: Balance,
+        min_out: Balance,
+    ) -> (Balance, Balance) {
+        // the average price only gets worse with size, so the fillable inputs are a range from 0
+        let fills = |input: Balance| {
+            U256::from(self.calc_swap(token_in, input).4) * U256::from(amount)
+                >= U256::from(min_out) * U256::from(input)
+        };
+        let (mut low, mut high) = (0, amount);
+        while low < high {
+            let mid = high - (high - low) / 2;
+            if fills(mid) {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        require!(low > 0, "slippage exceeded");
+        if low < amount {
+            log!("Filling {} of {} tokens", low, amount);
+        }
+        (low, mul_div_ceil(min_out, low, amount))
+    }
+
+    /// Quotes how many minimum units of the other token a swap of `amount_in` whole tokens of `token_in` yields,
+    /// using the same math as deposit_a and deposit_b. `get_amount_in` quotes the other way, in the same units.
+    pub fn get_amount_out(&self, token_in: AccountId, amount_in: U128) -> U128 {
+        require!(
+            token_in == self.a_contract_id || token_in == self.b_contract_id,
+            "token_in is not in the pool"
+        );
+        require!(self.metadata_loaded, "token metadata not loaded");
+        require!(
+            self.registered_a && self.registered_b,
+            "token registration not complete"
+        );
+        self.quote_amount_out(&token_in, amount_in.0)
+            .or_panic()
+            .into()
+    }
+
+    /// `get_amount_out` for each of `amounts_in`, all against the current reserves, e.g. to draw a depth chart.
+    pub fn get_amounts_out(&self, token_in: AccountId, amounts_in: Vec<U128>) -> Vec<U128> {
+        amounts_in
+            .into_iter()
+            .map(|amount_in| self.get_amount_out(token_in.clone(), amount_in))
+            .collect()
+    }
+
+    /// The fee in minimum units of `token_in` a swap of `amount_in` whole tokens of it pays, the
+    /// providers' and the protocol's parts together, as deposit_a and deposit_b charge it.
+    pub fn get_fee_amount(&self, token_in: AccountId, amount_in: U128) -> U128 {
+        // runs the same checks as the plain quote
+        self.get_amount_out(token_in.clone(), amount_in);
+        self.calc_swap_units(&token_in, self.to_units(&token_in, amount_in.0))
+            .1
+            .into()
+    }
+
+    /// The largest input in minimum units of `token_in` a swap can sell: it leaves the other reserve at
+    /// its floor, or without a floor takes at most `max_drain_bps` of it, half by default.
+    /// The max trade size of `token_in` caps it as well.
+    pub fn get_max_input(&self, token_in: AccountId, max_drain_bps: Option<u16>) -> U128 {
+        // runs the same checks as the plain quote
+        self.get_amount_out(token_in.clone(), U128(0));
+        let (y, min_reserve, decimals) = if token_in == self.a_contract_id {
+            (self.b_ticker, self.min_reserve_b, self.decimals().1)
+        } else {
+            (self.a_ticker, self.min_reserve_a, self.decimals().0)
+        };
+        let floor = if min_reserve > 0 {
+            min_reserve.saturating_mul(10_u128.pow(decimals as u32))
+        } else {
+            let max_drain_bps = max_drain_bps.unwrap_or(DEFAULT_MAX_DRAIN_BPS) as u128;
+            require!(
+                max_drain_bps <= BPS_DENOMINATOR,
+                "max_drain_bps must not exceed 10000"
+            );
+            mul_div_ceil(y, BPS_DENOMINATOR - max_drain_bps, BPS_DENOMINATOR)
+        };
+        // a larger input only leaves less of the other reserve, so the inputs that fit are a range from 0
+        let fits = |amount_in: Balance| {
+            self.try_calc_swap_units(&token_in, amount_in)
+                .is_ok_and(|(_, _, _, y_after, _)| y_after >= floor)
+        };
+        let (mut low, mut high) = (0, u128::MAX / BPS_DENOMINATOR);
+        while low < high {
+            let mid = high - (high - low) / 2;
+            if fits(mid) {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        let max_trade = if token_in == self.a_contract_id {
+            self.max_trade_a
+        } else {
+            self.max_trade_b
+        };
+        let max_trade = max_trade.map_or(Balance::MAX, |max_trade| {
+            self.to_units(&token_in, max_trade)
+        });
+        low.min(max_trade).into()
+    }
+
+    /// Quotes how many minimum units of `token_in` swapping `amount_in` whole tokens of it to the other
+    /// token and the whole output straight back would return. It never exceeds the input, the gap is
+    /// the fee paid twice plus rounding.
+    pub fn round_trip_quote(&self, token_in: AccountId, amount_in: U128) -> U128 {
+        // runs the same checks as the plain quote
+        self.get_amount_out(token_in.clone(), amount_in);
+        let (_, _, x_after, y_after, amount_out) = self.calc_swap(&token_in, amount_in.0);
+        // the pool after the first swap, whose K is recomputed from the tickers
+        let k = U256::from(x_after) * U256::from(y_after);
+        swap_units(y_after, x_after, k, self.active_fee_bps(), amount_out)
+            .or_panic()
+            .3
+            .into()
+    }
+
+    /// The average price a swap of `amount_in` whole tokens of `token_in` executes at, as the input over
+    /// the output in minimum units `(amount_in, amount_out)` reduced to lowest terms. Unlike the spot
+    /// price it grows with the size of the trade, and it includes the fee.
+    pub fn get_execution_price(&self, token_in: AccountId, amount_in: U128) -> (U128, U128) {
+        let amount_out = self.get_amount_out(token_in.clone(), amount_in).0;
+        require!(amount_out > 0, "output rounds to zero, increase amount");
+        let amount_in = self.to_units(&token_in, amount_in.0);
+        let divisor = gcd(amount_in, amount_out);
+        ((amount_in / divisor).into(), (amount_out / divisor).into())
+    }
+
+    /// Quotes a swap of `amount_in` whole tokens like `get_amount_out`, along with the spot price before
+    /// and after it, how far below the spot price it executes in basis points, and the fee it pays.
+    pub fn simulate_swap(&self, token_in: AccountId, amount_in: U128) -> SwapQuote {
+        // runs the same checks as the plain quote
+        self.get_amount_out(token_in.clone(), amount_in);
+        let (amount_in, fee, x_after, y_after, amount_out) = self.calc_swap(&token_in, amount_in.0);
+        let (x, y) = if token_in == self.a_contract_id {
+            (self.a_ticker, self.b_ticker)
+        } else {
+            (self.b_ticker, self.a_ticker)
+        };
+        let spot_out = mul_div(amount_in - fee, y, x);
+        let price_impact_bps = if spot_out == 0 {
+            0
+        } else {
+            mul_div(spot_out - amount_out, BPS_DENOMINATOR, spot_out) as u16
+        };
+        SwapQuote {
+            amount_out: amount_out.into(),
+            price_before: mul_div(y, PRICE_PRECISION, x).into(),
+            price_after: mul_div(y_after, PRICE_PRECISION, x_after).into(),
+            price_impact_bps,
+            fee_amount: fee.into(),
+        }
+    }
+
+    /// The user can transfer a certain number of tokens A to the contract account and
+    /// in return must receive a certain number of tokens B (similarly in the other direction).
+    /// The contract supports a certain ratio of tokens A and B. X * Y = K
+    /// K is some constant value, X and Y are the number of tokens A and B respectively.
+    /// `min_b_out` is the least amount of B (in minimum units) the caller accepts, otherwise the swap is rejected.
+    /// Without it the expected output less `default_slippage_bps` is the least accepted, an explicit
+    /// `min_b_out` overrides that default.
+    /// `deadline` is a nanosecond timestamp after which the swap is rejected,
+    /// callers should usually pass `env::block_timestamp() + 120_000_000_000` for a two-minute window.
+    /// `max_impact_bps` optionally caps how far, in basis points, the execution price may fall below
+    /// the spot price before the trade.
+    /// With `partial` set, an order that would miss `min_b_out` is filled only up to the largest amount
+    /// that still gets the price of `min_b_out` for `amount`, the rest is never pulled from the caller.
+    /// A `referrer`, e.g. the front-end, earns `referral_bps` of the fee left after the protocol fee.
+    /// The returned promise resolves to the amount of B transferred to the caller (0 if the swap failed).
+    #[payable]
+    pub fn deposit_a(
+        &mut self,
+        amount: U128,
+        min_b_out: Option<U128>,
+        deadline: u64,
+        max_impact_bps: Option<u16>,
+        partial: Option<bool>,
+        referrer: Option<AccountId>,
+    ) -> Promise {
+        check_amount(amount.0).or_panic();
+        self.check_not_paused().or_panic();
+        require!(!self.paused_a_to_b, "swaps from A to B are paused");
+        self.assert_allowed(&env::predecessor_account_id());
+        self.lock();
+        require!(env::block_timestamp() <= deadline, "deadline passed");
+        require!(self.metadata_loaded, "token metadata not loaded");
+        require!(
+            self.registered_a && self.registered_b,
+            "token registration not complete"
+        );
+        self.check_initialized().or_panic();
+        let sender_id = env::predecessor_account_id();
+        let min_b_out = min_b_out.map_or_else(
+            || self.default_min_out(&self.a_contract_id, amount.0),
+            |min_b_out| min_b_out.0,
+        );
+        let (amount, min_b_out) = if partial.unwrap_or(false) {
+            self.calc_partial_fill(&self.a_contract_id, amount.0, min_b_out)
+        } else {
+            (amount.0, min_b_out)
+        };
+        let (a_amount, a_fee, a_ticker_after, b_ticker_after, b_amount) =
+            self.calc_swap(&self.a_contract_id, amount);
+        self.check_max_trade(&self.a_contract_id, a_amount);
+        self.check_min_reserves(a_ticker_after, b_ticker_after);
+        require!(b_amount > 0, "output rounds to zero, increase amount");
+        check_slippage(b_amount, min_b_out).or_panic();
+        if let Some(max_impact_bps) = max_impact_bps {
+            self.check_price_impact(
+                &self.a_contract_id,
+                a_amount - a_fee,
+                b_amount,
+                max_impact_bps,
+            );
+        }
+        let next_contract = self.b_contract_id.clone();
+        if let Some(oracle_account) = self.oracle_account.clone() {
+            return self.oracle_price(oracle_account).then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas_for_oracle_swap())
+                    .callback_oracle_swap(
+                        a_ticker_after,
+                        b_ticker_after,
+                        next_contract,
+                        sender_id,
+                        a_amount,
+                        b_amount,
+                        a_fee,
+                        min_b_out,
+                        referrer,
+                    ),
+            );
+        }
+        self.request_swap_input(
+            a_ticker_after,
+            b_ticker_after,
+            next_contract,
+            sender_id,
+            a_amount,
+            b_amount,
+            a_fee,
+            min_b_out,
+            referrer,
+        )
+    }
+
+    /// The owner of the contract can transfer a certain amount of tokens A or B to the contract account,
+    /// thereby changing the ratio K.
+    #[payable]
+    pub fn deposit_a_by_owner(&mut self, amount: U128) {
+        check_amount(amount.0).or_panic();
+        self.assert_owner();
+        self.lock();
+        let a_amount = amount
+            .0
+            .checked_mul(10_u128.pow(self.decimals().0 as u32))
+            .expect("amount overflow");
+        let a_ticker_after = a_amount
+            .checked_add(self.a_ticker)
+            .expect("ticker overflow");
+        let b_ticker_after = self.b_ticker;
+        ext_token::ext(self.a_contract_id.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(self.owner_id.clone(), env::current_account_id(), a_amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_update_tickers(a_ticker_after, b_ticker_after, a_amount),
+            );
+    }
+
+    /// in the opposite direction
+    #[payable]
+    pub fn deposit_b(
+        &mut self,
+        amount: U128,
+        min_a_out: Option<U128>,
+        deadline: u64,
+        max_impact_bps: Option<u16>,
+        partial: Option<bool>,
+        referrer: Option<AccountId>,
+    ) -> Promise {
+        check_amount(amount.0).or_panic();
+        self.check_not_paused().or_panic();
+        require!(!self.paused_b_to_a, "swaps from B to A are paused");
+        self.assert_allowed(&env::predecessor_account_id());
+        self.lock();
+        require!(env::block_timestamp() <= deadline, "deadline passed");
+        require!(self.metadata_loaded, "token metadata not loaded");
+        require!(
+            self.registered_a && self.registered_b,
+            "token registration not complete"
+        );
+        self.check_initialized().or_panic();
+        let sender_id = env::predecessor_account_id();
+        let min_a_out = min_a_out.map_or_else(
+            || self.default_min_out(&self.b_contract_id, amount.0),
+            |min_a_out| min_a_out.0,
+        );
+        let (amount, min_a_out) = if partial.unwrap_or(false) {
+            self.calc_partial_fill(&self.b_contract_id, amount.0, min_a_out)
+        } else {
+            (amount.0, min_a_out)
+        };
+        let (b_amount, b_fee, b_ticker_after, a_ticker_after, a_amount) =
+            self.calc_swap(&self.b_contract_id, amount);
+        self.check_max_trade(&self.b_contract_id, b_amount);
+        self.check_min_reserves(a_ticker_after, b_ticker_after);
+        require!(a_amount > 0, "output rounds to zero, increase amount");
+        check_slippage(a_amount, min_a_out).or_panic();
+        if let Some(max_impact_bps) = max_impact_bps {
+            self.check_price_impact(
+                &self.b_contract_id,
+                b_amount - b_fee,
+                a_amount,
+                max_impact_bps,
+            );
+        }
+        let next_contract = self.a_contract_id.clone();
+        if let Some(oracle_account) = self.oracle_account.clone() {
+            return self.oracle_price(oracle_account).then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas_for_oracle_swap())
+                    .callback_oracle_swap(
+                        a_ticker_after,
+                        b_ticker_after,
+                        next_contract,
+                        sender_id,
+                        b_amount,
+                        a_amount,
+                        b_fee,
+                        min_a_out,
+                        referrer,
+                    ),
+            );
+        }
+        self.request_swap_input(
+            a_ticker_after,
+            b_ticker_after,
+            next_contract,
+            sender_id,
+            b_amount,
+            a_amount,
+            b_fee,
+            min_a_out,
+            referrer,
+        )
+    }
+
+    #[payable]
+    pub fn deposit_b_by_owner(&mut self, amount: U128) {
+        check_amount(amount.0).or_panic();
+        self.assert_owner();
+        self.lock();
+        let b_amount = amount
+            .0
+            .checked_mul(10_u128.pow(self.decimals().1 as u32))
+            .expect("amount overflow");
+        let b_ticker_after = b_amount
+            .checked_add(self.b_ticker)
+            .expect("ticker overflow");
+        let a_ticker_after = self.a_ticker;
+        ext_token::ext(self.b_contract_id.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(self.owner_id.clone(), env::current_account_id(), b_amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_update_tickers(a_ticker_after, b_ticker_after, b_amount),
+            );
+    }
+
+    /// The owner can take a certain amount of tokens A or B out of the contract account,
+    /// the inverse of `deposit_a_by_owner`, which changes the ratio K as well.
+    /// The reserves back the shares of every provider, so only while the owner holds all of them.
+    /// Requires 1 yoctoNEAR, as does `withdraw_b_by_owner`.
+    #[payable]
+    pub fn withdraw_a_by_owner(&mut self, amount: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.assert_sole_provider();
+        self.lock();
+        let a_amount = amount
+            .0
+            .checked_mul(10_u128.pow(self.decimals().0 as u32))
+            .expect("amount overflow");
+        let a_ticker_after = self
+            .a_ticker
+            .checked_sub(a_amount)
+            .expect("insufficient A liquidity for this withdrawal");
+        let b_ticker_after = self.b_ticker;
+        ext_token::ext(self.a_contract_id.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(env::current_account_id(), self.owner_id.clone(), a_amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_update_tickers(a_ticker_after, b_ticker_after, a_amount),
+            );
+    }
+
+    #[payable]
+    pub fn withdraw_b_by_owner(&mut self, amount: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.assert_sole_provider();
+        self.lock();
+        let b_amount = amount
+            .0
+            .checked_mul(10_u128.pow(self.decimals().1 as u32))
+            .expect("amount overflow");
+        let b_ticker_after = self
+            .b_ticker
+            .checked_sub(b_amount)
+            .expect("insufficient B liquidity for this withdrawal");
+        let a_ticker_after = self.a_ticker;
+        ext_token::ext(self.b_contract_id.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(env::current_account_id(), self.owner_id.clone(), b_amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_update_tickers(a_ticker_after, b_ticker_after, b_amount),
+            );
+    }
+
+    /// Moves both reserves to `a_target` and `b_target` minimum units at once: the owner sends in what
+    /// a reserve lacks and gets back what it has over its target, then both tickers are updated together.
+    /// A leg the owner can't cover fails in the token contract and leaves that reserve as it was.
+    /// Like `close_pool` it is only for a pool whose shares the owner holds all of, and requires 1 yoctoNEAR.
+    /// The returned promise resolves to the reserves afterwards.
+    #[payable]
+    pub fn rebalance(&mut self, a_target: U128, b_target: U128) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        self.assert_sole_provider();
+        require!(a_target.0 > 0 && b_target.0 > 0, "targets must be positive");
+        require!(
+            a_target.0 != self.a_ticker || b_target.0 != self.b_ticker,
+            "reserves are already at the targets"
+        );
+        self.lock();
+        self.rebalance_transfers(a_target.0, b_target.0).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(self.callback_gas)
+                .callback_rebalance(a_target.0, b_target.0),
+        )
+    }
+
+    /// The transfers taking the reserves to the targets, joined A first, at least one reserve has to move.
+    fn rebalance_transfers(&self, a_target: Balance, b_target: Balance) -> Promise {
+        let a_leg = self.rebalance_leg(&self.a_contract_id, self.a_ticker, a_target);
+        let b_leg = self.rebalance_leg(&self.b_contract_id, self.b_ticker, b_target);
+        match (a_leg, b_leg) {
+            (Some(a_leg), Some(b_leg)) => a_leg.and(b_leg),
+            (Some(leg), None) | (None, Some(leg)) => leg,
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// The transfer between the owner and the pool taking a reserve of `token` from `ticker` to `target`,
+    /// none if it is there already.
+    fn rebalance_leg(
+        &self,
+        token: &AccountId,
+        ticker: Balance,
+        target: Balance,
+    ) -> Option<Promise> {
+        let (sender_id, receiver_id, amount) = if target > ticker {
+            (
+                self.owner_id.clone(),
+                env::current_account_id(),
+                target - ticker,
+            )
+        } else if target < ticker {
+            (
+                env::current_account_id(),
+                self.owner_id.clone(),
+                ticker - target,
+            )
+        } else {
+            return None;
+        };
+        Some(
+            ext_token::ext(token.clone())
+                .with_static_gas(GAS_FOR_TRANSFER_FROM)
+                .transfer_from(sender_id, receiver_id, amount),
+        )
+    }
+
+    /// Sets the ticker of every reserve whose transfer succeeded to its target and recomputes the ratio once.
+    pub fn callback_rebalance(&mut self, a_target: Balance, b_target: Balance) -> (U128, U128) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        self.settle_rebalance(a_target, b_target);
+        (self.a_ticker.into(), self.b_ticker.into())
+    }
+
+    /// Winds the pool down and sends both reserves back to the owner, whose shares are burnt.
+    /// Only a paused pool whose shares all belong to the owner can be closed, so no liquidity provider
+    /// loses its part of the reserves. The fees stay for `collect_fees` and `collect_protocol_fees`.
+    /// Requires 1 yoctoNEAR. The returned promise resolves to the reserves afterwards, (0, 0) once closed.
+    #[payable]
+    pub fn close_pool(&mut self) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(self.paused, "the pool must be paused to close it");
+        self.assert_sole_provider();
+        require!(
+            self.a_ticker > 0 || self.b_ticker > 0,
+            "the pool is closed already"
+        );
+        self.lock();
+        self.rebalance_transfers(0, 0).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(self.callback_gas)
+                .callback_close_pool(),
+        )
+    }
+
+    pub fn callback_close_pool(&mut self) -> (U128, U128) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        self.settle_rebalance(0, 0);
+        // a failed transfer leaves its reserve, so the owner can close the pool again
+        if self.a_ticker == 0 && self.b_ticker == 0 {
+            self.set_shares(&self.owner_id.clone(), 0);
+            self.total_shares = 0;
+            log!("The pool is closed");
+        }
+        (self.a_ticker.into(), self.b_ticker.into())
+    }
+
+    /// Sets the ticker of every reserve whose transfer from `rebalance_transfers` succeeded to its target.
+    fn settle_rebalance(&mut self, a_target: Balance, b_target: Balance) {
+        // the legs were joined A first, and only for the reserves that were off their targets
+        let mut results = (0..env::promise_results_count())
+            .map(|i| matches!(env::promise_result(i), PromiseResult::Successful(_)));
+        let a_moved = a_target != self.a_ticker && results.next().unwrap_or(false);
+        let b_moved = b_target != self.b_ticker && results.next().unwrap_or(false);
+        if a_target != self.a_ticker && !a_moved {
+            log!("Transfer of A failed, the A reserve is not rebalanced");
+        }
+        if b_target != self.b_ticker && !b_moved {
+            log!("Transfer of B failed, the B reserve is not rebalanced");
+        }
+        if a_moved || b_moved {
+            self.update_price_cumulative();
+            if a_moved {
+                self.a_ticker = a_target;
+            }
+            if b_moved {
+                self.b_ticker = b_target;
+            }
+            self.calc_ratio();
+            events::Sync {
+                a_ticker: self.a_ticker.into(),
+                b_ticker: self.b_ticker.into(),
+            }
+            .emit();
+        }
+    }
+
+    /// Swaps the attached NEAR for the other token of the pool: the deposit is wrapped with the wrapped NEAR
+    /// token of the pool, see `set_wrap_contract`, and the whole of it is the input of the swap.
+    /// `min_out` is the least output in minimum units the caller accepts, otherwise the swap is rejected.
+    /// The deposit is refunded if the wrapping fails. The returned promise resolves to the output sent.
+    #[payable]
+    pub fn deposit_near(&mut self, min_out: U128) -> Promise {
+        let amount = env::attached_deposit();
+        check_amount(amount).or_panic();
+        let token_in = self
+            .wrap_contract_id
+            .clone()
+            .expect("wrapped NEAR is not configured");
+        // the wrapping has no oracle step, so it fails closed while the guard is set
+        require!(
+            self.oracle_account.is_none(),
+            "deposit_near is unavailable while the oracle guard is set"
+        );
+        self.check_not_paused().or_panic();
+        if token_in == self.a_contract_id {
+            require!(!self.paused_a_to_b, "swaps from A to B are paused");
+        } else {
+            require!(!self.paused_b_to_a, "swaps from B to A are paused");
+        }
+        let sender_id = env::predecessor_account_id();
+        self.assert_allowed(&sender_id);
+        self.lock();
+        require!(self.metadata_loaded, "token metadata not loaded");
+        require!(
+            self.registered_a && self.registered_b,
+            "token registration not complete"
+        );
+        self.check_initialized().or_panic();
+        let (amount_in, fee, x_after, y_after, amount_out) =
+            self.calc_swap_units(&token_in, amount);
+        self.check_max_trade(&token_in, amount_in);
+        require!(amount_out > 0, "output rounds to zero, increase amount");
+        check_slippage(amount_out, min_out.0).or_panic();
+        let (a_ticker_after, b_ticker_after, token_out) = if token_in == self.a_contract_id {
+            (x_after, y_after, self.b_contract_id.clone())
+        } else {
+            (y_after, x_after, self.a_contract_id.clone())
+        };
+        self.check_min_reserves(a_ticker_after, b_ticker_after);
+        ext_wrap::ext(token_in)
+            .with_attached_deposit(amount_in)
+            .with_static_gas(GAS_FOR_NEAR_DEPOSIT)
+            .near_deposit()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas_for_swap_output())
+                    .callback_near_deposit(
+                        a_ticker_after,
+                        b_ticker_after,
+                        token_out,
+                        sender_id,
+                        amount_in,
+                        amount_out,
+                        fee,
+                    ),
+            )
+    }
+
+    /// Sends `amount` of `contract_id`, the output of `deposit_near`, once the NEAR is wrapped, or refunds the NEAR, which a failed
+    /// `near_deposit` returned to the pool.
+    pub fn callback_near_deposit(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        #[callback_result] deposit_result: Result<(), PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        if deposit_result.is_err() {
+            log!(
+                "Wrapping failed, refunding {} to {}",
+                amount_in,
+                receiver_id
+            );
+            self.locked = false;
+            Promise::new(receiver_id).transfer(amount_in);
+            return PromiseOrValue::Value(U128(0));
+        }
+        self.send_swap_output(
+            a_ticker_after,
+            b_ticker_after,
+            contract_id,
+            receiver_id,
+            amount_in,
+            amount,
+            fee,
+            None,
+        )
+    }
+
+    /// Routing entry point: swaps `amount_in` whole tokens of `token_in` for the other token of the pool.
+    /// Only this pool is a hop for now, the returned promise resolves to the output like `deposit_a`
+    /// and is where the swap on a following pool will be chained.
+    #[payable]
+    pub fn swap_route(&mut self, token_in: AccountId, amount_in: U128, min_out: U128) -> Promise {
+        require!(
+            token_in == self.a_contract_id || token_in == self.b_contract_id,
+            "token_in is not in the pool"
+        );
+        if token_in == self.a_contract_id {
+            self.deposit_a(amount_in, Some(min_out), u64::MAX, None, None, None)
+        } else {
+            self.deposit_b(amount_in, Some(min_out), u64::MAX, None, None, None)
+        }
+    }
+
+    /// Swaps as much of one token as moves the spot price, in whole B per whole A as `get_spot_price`
+    /// returns it, to `target_price_num / target_price_den`: A is sold for a lower price and B for a
+    /// higher one, the fee is added on top, so the reserves land on the target up to rounding.
+    /// The output may fall `default_slippage_bps` short of the quote, like a swap without `min_out`.
+    /// The returned promise resolves to the output amount like `deposit_a`.
+    #[payable]
+    pub fn swap_to_price(&mut self, target_price_num: U128, target_price_den: U128) -> Promise {
+        require!(
+            target_price_num.0 > 0 && target_price_den.0 > 0,
+            "target price must be positive"
+        );
+        self.check_not_paused().or_panic();
+        self.assert_allowed(&env::predecessor_account_id());
+        require!(self.metadata_loaded, "token metadata not loaded");
+        require!(
+            self.registered_a && self.registered_b,
+            "token registration not complete"
+        );
+        self.check_initialized().or_panic();
+        let target_input = self.calc_price_target_input(target_price_num.0, target_price_den.0);
+        require!(
+            target_input.is_some(),
+            "the spot price is already at the target price"
+        );
+        let (token_in, amount_in) = target_input.unwrap();
+        if token_in == self.a_contract_id {
+            require!(!self.paused_a_to_b, "swaps from A to B are paused");
+        } else {
+            require!(!self.paused_b_to_a, "swaps from B to A are paused");
+        }
+        self.lock();
+        let sender_id = env::predecessor_account_id();
+        let (amount_in, fee, x_after, y_after, amount_out) =
+            self.calc_swap_units(&token_in, amount_in);
+        let (a_ticker_after, b_ticker_after, next_contract) = if token_in == self.a_contract_id {
+            (x_after, y_after, self.b_contract_id.clone())
+        } else {
+            (y_after, x_after, self.a_contract_id.clone())
+        };
+        self.check_max_trade(&token_in, amount_in);
+        self.check_min_reserves(a_ticker_after, b_ticker_after);
+        require!(amount_out > 0, "output rounds to zero, increase amount");
+        let min_out = mul_div(
+            amount_out,
+            BPS_DENOMINATOR - self.default_slippage_bps as u128,
+            BPS_DENOMINATOR,
+        );
+        if let Some(oracle_account) = self.oracle_account.clone() {
+            return self.oracle_price(oracle_account).then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas_for_oracle_swap())
+                    .callback_oracle_swap(
+                        a_ticker_after,
+                        b_ticker_after,
+                        next_contract,
+                        sender_id,
+                        amount_in,
+                        amount_out,
+                        fee,
+                        min_out,
+                        None,
+                    ),
+            );
+        }
+        self.request_swap_input(
+            a_ticker_after,
+            b_ticker_after,
+            next_contract,
+            sender_id,
+            amount_in,
+            amount_out,
+            fee,
+            min_out,
+            None,
+        )
+    }
+
+    /// Continues a swap of `deposit_a` or `deposit_b` if the pool's spot price is within the oracle
+    /// tolerance of the oracle price, otherwise cancels it before anything was pulled.
+    pub fn callback_oracle_swap(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        min_out: Balance,
+        referrer: Option<AccountId>,
+        #[callback_result] price: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        if !self.oracle_allows(price) {
+            log!("Swap for {} cancelled", receiver_id);
+            self.locked = false;
+            return PromiseOrValue::Value(U128(0));
+        }
+        self.request_swap_input(
+            a_ticker_after,
+            b_ticker_after,
+            contract_id,
+            receiver_id,
+            amount_in,
+            amount,
+            fee,
+            min_out,
+            referrer,
+        )
+        .into()
+    }
+
+    /// Sends the output of a swap by `ft_transfer_call` if the pool's spot price is within the oracle
+    /// tolerance of the oracle price. Returns the amount the token refunds, all of it for a cancelled swap.
+    pub fn callback_oracle_transfer(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        #[callback_result] price: Result<U128, PromiseError>,
+    ) -> U128 {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        if !self.oracle_allows(price) {
+            log!(
+                "Swap for {} cancelled, refunding {}",
+                receiver_id,
+                amount_in
+            );
+            self.locked = false;
+            return U128(amount_in);
+        }
+        self.send_swap_output(
+            a_ticker_after,
+            b_ticker_after,
+            contract_id,
+            receiver_id,
+            amount_in,
+            amount,
+            fee,
+            None,
+        );
+        U128(0)
+    }
+
+    /// Asks `oracle_account` for the price of A in B.
+    fn oracle_price(&self, oracle_account: AccountId) -> Promise {
+        ext_oracle::ext(oracle_account)
+            .with_static_gas(GAS_FOR_VIEW)
+            .get_price(self.a_contract_id.clone(), self.b_contract_id.clone())
+    }
+
+    /// Whether the spot price of A in B is within `oracle_tolerance_bps` of the oracle price.
+    /// A failed or zero price fails closed.
+    fn oracle_allows(&self, price: Result<U128, PromiseError>) -> bool {
+        let oracle_price = match price {
+            Ok(price) if price.0 > 0 => U256::from(price.0),
+            _ => {
+                log!("Oracle price unavailable");
+                return false;
+            }
+        };
+        let (a_decimals, b_decimals) = self.decimals();
+        let spot_price = U256::from(self.b_ticker)
+            * U256::from(10_u128.pow(a_decimals as u32))
+            * U256::from(PRICE_PRECISION)
+            / (U256::from(self.a_ticker) * U256::from(10_u128.pow(b_decimals as u32)));
+        let deviation = if spot_price > oracle_price {
+            spot_price - oracle_price
+        } else {
+            oracle_price - spot_price
+        };
+        if deviation * U256::from(BPS_DENOMINATOR)
+            > oracle_price * U256::from(self.oracle_tolerance_bps)
+        {
+            log!(
+                "Spot price deviates from the oracle by more than {} bps",
+                self.oracle_tolerance_bps
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Queries the balance of the input token of a swap for `callback_ft_deposit_balance`.
+    fn request_swap_input(
+        &self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        min_out: Balance,
+        referrer: Option<AccountId>,
+    ) -> Promise {
+        let token_in = if contract_id == self.b_contract_id {
+            self.a_contract_id.clone()
+        } else {
+            self.b_contract_id.clone()
+        };
+        ext_token::ext(token_in)
+            .with_static_gas(GAS_FOR_VIEW)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas_for_pull_swap_input())
+                    .callback_ft_deposit_balance(
+                        a_ticker_after,
+                        b_ticker_after,
+                        contract_id,
+                        receiver_id,
+                        amount_in,
+                        amount,
+                        fee,
+                        min_out,
+                        referrer,
+                    ),
+            )
+    }
+
+    /// Runs with the pool's balance of the input token before the swap and pulls the input,
+    /// followed by the balance after it, so the swap is priced on what actually arrived.
+    /// `contract_id` is the output token and `fee` is charged in the input token.
+    pub fn callback_ft_deposit_balance(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        min_out: Balance,
+        referrer: Option<AccountId>,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        let balance_before = match balance {
+            Ok(balance) => balance.0,
+            Err(_) => {
+                log!("Balance query failed, swap for {} cancelled", receiver_id);
+                self.locked = false;
+                return PromiseOrValue::Value(U128(0));
+            }
+        };
+        let token_in = if contract_id == self.b_contract_id {
+            self.a_contract_id.clone()
+        } else {
+            self.b_contract_id.clone()
+        };
+        ext_token::ext(token_in.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(receiver_id.clone(), env::current_account_id(), amount_in)
+            .then(
+                ext_token::ext(token_in)
+                    .with_static_gas(GAS_FOR_VIEW)
+                    .ft_balance_of(env::current_account_id()),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas_for_swap_output())
+                    .callback_ft_deposit(
+                        a_ticker_after,
+                        b_ticker_after,
+                        contract_id,
+                        receiver_id,
+                        amount_in,
+                        amount,
+                        fee,
+                        min_out,
+                        balance_before,
+                        referrer,
+                    ),
+            )
+            .into()
+    }
+
+    /// Runs after the inbound transfer_from, the outbound transfer is only sent when the input arrived.
+    /// A token taking a fee on transfer delivers less than `amount_in`, then the swap is priced again
+    /// on the amount received, or that amount is refunded if the output misses `min_out`.
+    /// When the balance query itself fails, whether the input arrived is unknown, so the pool stays
+    /// locked for the owner to check the transfer, refund it and `force_unlock`.
+    pub fn callback_ft_deposit(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        min_out: Balance,
+        balance_before: Balance,
+        referrer: Option<AccountId>,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        let balance = match balance {
+            Ok(balance) => balance.0,
+            Err(_) => {
+                log!(
+                    "Balance query failed, the inbound transfer of {} for {} is unconfirmed and the pool stays locked",
+                    amount_in,
+                    receiver_id
+                );
+                return PromiseOrValue::Value(U128(0));
+            }
+        };
+        // a failed transfer leaves the balance as it was
+        let received = balance.saturating_sub(balance_before);
+        if received == 0 {
+            log!(
+                "Inbound transfer failed, swap for {} cancelled",
+                receiver_id
+            );
+            self.locked = false;
+            return PromiseOrValue::Value(U128(0));
+        }
+        if received < amount_in {
+            let token_in = if contract_id == self.b_contract_id {
+                self.a_contract_id.clone()
+            } else {
+                self.b_contract_id.clone()
+            };
+            let (_, fee, x_after, y_after, amount) = self.calc_swap_units(&token_in, received);
+            if amount == 0 || amount < min_out {
+                log!(
+                    "Only {} of {} arrived, refunding {}",
+                    received,
+                    amount_in,
+                    receiver_id
+                );
+                self.locked = false;
+                self.refund_swap_input(token_in, receiver_id, received);
+                return PromiseOrValue::Value(U128(0));
+            }
+            let (a_ticker_after, b_ticker_after) = if token_in == self.a_contract_id {
+                (x_after, y_after)
+            } else {
+                (y_after, x_after)
+            };
+            return self.send_swap_output(
+                a_ticker_after,
+                b_ticker_after,
+                contract_id,
+                receiver_id,
+                received,
+                amount,
+                fee,
+                referrer,
+            );
+        }
+        self.send_swap_output(
+            a_ticker_after,
+            b_ticker_after,
+            contract_id,
+            receiver_id,
+            amount_in,
+            amount,
+            fee,
+            referrer,
+        )
+    }
+
+    /// Splits `fee` of `token` into the protocol's part, the referrer's part of the rest and what is
+    /// left for the liquidity providers, which goes to the shares in proportion.
+    fn credit_fee(&mut self, token: &AccountId, fee: Balance, referrer: Option<&AccountId>) {
+        let protocol_fee = mul_div(fee, self.protocol_fee_bps as u128, BPS_DENOMINATOR);
+        let referral_fee = referrer.map_or(0, |_| {
+            mul_div(
+                fee - protocol_fee,
+                self.referral_bps as u128,
+                BPS_DENOMINATOR,
+            )
+        });
+        let lp_fee = fee - protocol_fee - referral_fee;
+        // with no shares there is nobody to earn it, so it stays unclaimable in the fees
+        let fee_per_share = if self.total_shares > 0 {
+            U256::from(lp_fee) * U256::from(FEE_PER_SHARE_PRECISION) / U256::from(self.total_shares)
+        } else {
+            U256::zero()
+        };
+        if *token == self.a_contract_id {
+            self.a_protocol_fees += protocol_fee;
+            self.a_referral_fees += referral_fee;
+            self.a_fees += lp_fee;
+            self.a_fee_per_share += fee_per_share;
+        } else {
+            self.b_protocol_fees += protocol_fee;
+            self.b_referral_fees += referral_fee;
+            self.b_fees += lp_fee;
+            self.b_fee_per_share += fee_per_share;
+        }
+        if let Some(referrer) = referrer.filter(|_| referral_fee > 0) {
+            let (a_fees, b_fees) = self.referral_fees.get(referrer).unwrap_or((0, 0));
+            let fees = if *token == self.a_contract_id {
+                (a_fees + referral_fee, b_fees)
+            } else {
+                (a_fees, b_fees + referral_fee)
+            };
+            self.referral_fees.insert(referrer, &fees);
+        }
+    }
+
+    /// Finishes a swap whose input has arrived: sends `amount` of `contract_id` to `receiver_id`, and keeps
+    /// the fee and commits the tickers once that transfer succeeded.
+    /// If the swap would trip the circuit breaker, the pool is paused and the input refunded instead.
+    fn send_swap_output(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        referrer: Option<AccountId>,
+    ) -> PromiseOrValue<U128> {
+        if self.trip_breaker(a_ticker_after, b_ticker_after) {
+            log!(
+                "Swap for {} would trip the circuit breaker, refunding {}",
+                receiver_id,
+                amount_in
+            );
+            self.locked = false;
+            let token_in = if contract_id == self.b_contract_id {
+                self.a_contract_id.clone()
+            } else {
+                self.b_contract_id.clone()
+            };
+            self.refund_swap_input(token_in, receiver_id, amount_in);
+            return PromiseOrValue::Value(U128(0));
+        }
+        ext_token::ext(contract_id.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(env::current_account_id(), receiver_id.clone(), amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas_for_swap_refund())
+                    .callback_swap_output(
+                        a_ticker_after,
+                        b_ticker_after,
+                        contract_id,
+                        receiver_id,
+                        amount_in,
+                        amount,
+                        fee,
+                        referrer,
+                    ),
+            )
+            .into()
+    }
+
+    /// Keeps the fee, records the trade and commits the tickers of a swap once its output was sent,
+    /// otherwise returns the `amount_in` of the input token, which has arrived, to `receiver_id`.
+    /// Returns the `amount` of the output sent, or 0 when the transfer failed.
+    pub fn callback_swap_output(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        referrer: Option<AccountId>,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> U128 {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        let token_in = if contract_id == self.b_contract_id {
+            self.a_contract_id.clone()
+        } else {
+            self.b_contract_id.clone()
+        };
+        if transfer_result.is_err() {
+            log!(
+                "Output transfer failed, refunding {} to {}",
+                amount_in,
+                receiver_id
+            );
+            self.refund_swap_input(token_in, receiver_id, amount_in);
+            return U128(0);
+        }
+        // the output has left, so the fee of the input belongs to the pool now
+        self.credit_fee(&token_in, fee, referrer.as_ref());
+        if token_in == self.a_contract_id {
+            self.volume_a_in += amount_in;
+        } else {
+            self.volume_b_in += amount_in;
+        }
+        events::Swap {
+            account_id: &receiver_id,
+            token_in: &token_in,
+            amount_in: &U128(amount_in),
+            token_out: &contract_id,
+            amount_out: &U128(amount),
+        }
+        .emit();
+        let (a_decimals, b_decimals) = self.decimals();
+        let (decimals_in, decimals_out) = if token_in == self.a_contract_id {
+            (a_decimals, b_decimals)
+        } else {
+            (b_decimals, a_decimals)
+        };
+        log!(
+            "Swapped {} {} for {} {} to {}",
+            format_units(amount_in, decimals_in),
+            token_in,
+            format_units(amount, decimals_out),
+            contract_id,
+            receiver_id
+        );
+        self.record_trade(TradeRecord {
+            timestamp: env::block_timestamp(),
+            token_in,
+            amount_in: amount_in.into(),
+            amount_out: amount.into(),
+            account_id: receiver_id,
+        });
+        self.commit_tickers(a_ticker_after, b_ticker_after);
+        amount.into()
+    }
+
+    /// Returns the `amount_in` of `token_in` a swap pulled to `receiver_id`.
+    fn refund_swap_input(&self, token_in: AccountId, receiver_id: AccountId, amount_in: Balance) {
+        ext_token::ext(token_in.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(env::current_account_id(), receiver_id.clone(), amount_in)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_swap_refund(token_in, receiver_id, amount_in),
+            );
+    }
+
+    /// Logs a swap refund that failed, the tokens are then held by the pool outside the tickers and the
+    /// owner has to return them, e.g. after `sync`.
+    pub fn callback_swap_refund(
+        &mut self,
+        token_in: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        if transfer_result.is_err() {
+            log!(
+                "Refund of {} {} to {} failed, the tokens are held by the pool",
+                amount_in,
+                token_in,
+                receiver_id
+            );
+        }
+    }
+
+    /// Commits the new tickers once the preceding transfer_from succeeded, otherwise leaves them untouched.
+    /// Returns the `amount` moved by that transfer, or 0 when it failed.
+    pub fn callback_update_tickers(
+        &mut self,
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        amount: Balance,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> U128 {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        // the owner deposit or withdrawal in flight ends here either way
+        self.locked = false;
+        if transfer_result.is_err() {
+            log!("Transfer failed, tickers are not updated");
+            return U128(0);
+        }
+        self.commit_tickers(a_ticker_after, b_ticker_after);
+        amount.into()
+    }
+
+    /// Sets the tickers after a transfer moved the reserves, or pauses the pool instead if the move
+    /// trips the circuit breaker.
+    fn commit_tickers(&mut self, a_ticker_after: Balance, b_ticker_after: Balance) {
+        // the tokens have moved already, so the owner has to reconcile the tickers before unpausing
+        if self.trip_breaker(a_ticker_after, b_ticker_after) {
+            return;
+        }
+        // the old reserves priced the pool until now
+        self.update_price_cumulative();
+        self.a_ticker = a_ticker_after;
+        self.b_ticker = b_ticker_after;
+        self.last_update_block = env::block_height();
+        self.calc_ratio();
+        events::Sync {
+            a_ticker: self.a_ticker.into(),
+            b_ticker: self.b_ticker.into(),
+        }
+        .emit();
+    }
+
+    /// Approves `receiver` for flash swaps or revokes it.
+    pub fn set_flash_receiver(&mut self, receiver: AccountId, approved: bool) {
+        self.assert_owner();
+        if approved {
+            self.flash_receivers.insert(&receiver);
+        } else {
+            self.flash_receivers.remove(&receiver);
+        }
+    }
+
+    /// Lends `amount` minimum units of `token_out` to `receiver` and calls its `on_flash_swap` with `msg`,
+    /// which has to send back `amount` plus the swap fee in `token_out`.
+    /// The repayment is verified against the pool's balance before the loan.
+    /// Receipts on NEAR can't be reverted once the tokens left, so only receivers approved by the owner
+    /// may borrow, and a short repayment is taken off the reserve and pauses the pool.
+    /// The returned promise resolves to whether the loan was repaid in full.
+    pub fn flash_swap(
+        &mut self,
+        token_out: AccountId,
+        amount: U128,
+        receiver: AccountId,
+        msg: String,
+    ) -> Promise {
+        self.check_not_paused().or_panic();
+        require!(
+            self.flash_receivers.contains(&receiver),
+            "receiver is not approved for flash swaps"
+        );
+        let reserve = if token_out == self.a_contract_id {
+            self.a_ticker
+        } else if token_out == self.b_contract_id {
+            self.b_ticker
+        } else {
+            env::panic_str("token_out is not in the pool")
+        };
+        check_amount(amount.0).or_panic();
+        require!(amount.0 < reserve, "amount must be less than the reserve");
+        // swaps would move the balance the repayment is checked against
+        self.lock();
+        ext_token::ext(token_out.clone())
+            .with_static_gas(GAS_FOR_VIEW)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas_for_flash_loan())
+                    .callback_flash_swap_lend(token_out, amount.0, receiver, msg),
+            )
+    }
+
+    /// Runs with the pool's balance before the loan and sends the loan, followed by the receiver's
+    /// repayment and the balance after it.
+    pub fn callback_flash_swap_lend(
+        &mut self,
+        token_out: AccountId,
+        amount: Balance,
+        receiver: AccountId,
+        msg: String,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<bool> {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        let balance_before = match balance {
+            Ok(balance) => balance.0,
+            Err(_) => {
+                log!("Balance query failed, nothing is lent");
+                self.locked = false;
+                return PromiseOrValue::Value(false);
+            }
+        };
+        let fee = mul_div_ceil(amount, self.active_fee_bps() as u128, BPS_DENOMINATOR);
+        // if the loan doesn't arrive, the receiver has nothing to repay and the balance stays as before
+        ext_token::ext(token_out.clone())
+            .with_static_gas(GAS_FOR_TRANSFER_FROM)
+            .transfer_from(env::current_account_id(), receiver.clone(), amount)
+            .then(
+                ext_flash_receiver::ext(receiver)
+                    .with_static_gas(GAS_FOR_ON_FLASH_SWAP)
+                    .on_flash_swap(token_out.clone(), amount.into(), (amount + fee).into(), msg),
+            )
+            .then(
+                ext_token::ext(token_out.clone())
+                    .with_static_gas(GAS_FOR_VIEW)
+                    .ft_balance_of(env::current_account_id()),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.callback_gas)
+                    .callback_flash_swap(token_out, amount, fee, balance_before),
+            )
+            .into()
+    }
+
+    /// Checks the repayment of a flash swap against the balance after it, crediting the fee.
+    pub fn callback_flash_swap(
+        &mut self,
+        token_out: AccountId,
+        amount: Balance,
+        fee: Balance,
+        balance_before: Balance,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> bool {
+        require!(
+            env::predecessor_account_id() == env::current_account_id(),
+            "only support to call by itself"
+        );
+        self.locked = false;
+        let balance_after = match balance {
+            Ok(balance) => balance.0,
+            Err(_) => {
+                log!("Balance query failed, pausing until the owner checks the repayment");
+                self.paused = true;
+                return false;
+            }
+        };
+        let received = balance_after.saturating_sub(balance_before);
+        let shortfall = (fee + balance_before).saturating_sub(balance_after);
+        // anything short of the fee is only a smaller fee, the rest is lost from the reserve
+        let (fees, loss) = if shortfall <= fee {
+            (fee - shortfall, 0)
+        } else {
+            (0, shortfall - fee)
+        };
+        if loss > 0 {
+            // the old reserves priced the pool until now
+            self.update_price_cumulative();
+        }
+        self.credit_fee(&token_out, fees, None);
+        if token_out == self.a_contract_id {
+            self.a_ticker -= loss;
+        } else {
+            self.b_ticker -= loss;
+        }
+        if shortfall == 0 {
+            return true;
+        }
+        log!(
+            "Flash swap of {} repaid {} short, {} received",
+            amount,
+            shortfall,
+            received
+        );
+        if loss > 0 {
+            self.calc_ratio();
+            self.paused = true;
+        }
+        false
+    }
+
+    /// The (A, B) sold to the pool in all swaps so far, in minimum units with the fees.
+    /// Owner deposits, liquidity and flash swaps are not swap volume.
+    pub fn get_volume(&self) -> (U128, U128) {
+        (self.volume_a_in.into(), self.volume_b_in.into())
+    }
+
+    /// Up to `limit` of the most recent swaps, the newest last.
+    pub fn get_recent_trades(&self, limit: u32) -> Vec<TradeRecord> {
+        let len = self.trades.len();
+        // once the buffer is full, the oldest trade sits where the next one will go
+        let oldest = if len < TRADE_HISTORY_SIZE {
+            0
+        } else {
+            self.trade_head
+        };
+        let count = len.min(limit as u64);
+        (len - count..len)
+            .map(|i| self.trades.get((oldest + i) % len).unwrap())
+            .collect()
+    }
+
+    fn record_trade(&mut self, trade: TradeRecord) {
+        if self.trades.len() < TRADE_HISTORY_SIZE {
+            self.trades.push(&trade);
+        } else {
+            self.trades.replace(self.trade_head, &trade);
+        }
+        self.trade_head = (self.trade_head + 1) % TRADE_HISTORY_SIZE;
+    }
+
+    /// Pauses the pool and returns true when moving the tickers to these would trip the circuit breaker.
+    fn trip_breaker(&mut self, a_ticker_after: Balance, b_ticker_after: Balance) -> bool {
+        if !self.breaker_trips(self.a_ticker, a_ticker_after)
+            && !self.breaker_trips(self.b_ticker, b_ticker_after)
+        {
+            return false;
+        }
+        self.paused = true;
+        events::CircuitBreakerTripped {
+            a_ticker: self.a_ticker.into(),
+            b_ticker: self.b_ticker.into(),
+            a_ticker_after: a_ticker_after.into(),
+            b_ticker_after: b_ticker_after.into(),
+        }
+        .emit();
+        true
+    }
+
+    fn breaker_trips(&self, ticker: Balance, ticker_after: Balance) -> bool {
+        self.breaker_bps > 0
+            && U256::from(ticker.abs_diff(ticker_after)) * U256::from(BPS_DENOMINATOR)
+                > U256::from(ticker) * U256::from(self.breaker_bps)
+    }
+
+    /// (cumulative price of A in B, cumulative price of B in A, timestamp of the last update in nanoseconds).
+    /// Prices are reserve ratios in minimum units scaled by 10^12 and summed once per second, so the
+    /// average price between two samples is the difference of the sums divided by the seconds between them.
+    /// The sums wrap around on overflow, which the difference of two samples tolerates.
+    pub fn get_price_cumulative(&self) -> (U128, U128, u64) {
+        (
+            self.price_cumulative_a.into(),
+            self.price_cumulative_b.into(),
+            self.last_price_timestamp,
+        )
+    }
+
+    /// The block height at which a swap or owner deposit last changed the reserves, so integrators can
+    /// tell how stale `get_reserves` is.
+    pub fn get_last_update(&self) -> u64 {
+        self.last_update_block
+    }
+
+    /// Sums the current price into the cumulative prices for the time since their last update, which
+    /// otherwise only happens when the reserves change. Anyone can call it to keep the oracle fresh
+    /// while nobody trades.
+    pub fn poke(&mut self) {
+        self.update_price_cumulative();
+    }
+
+    fn update_price_cumulative(&mut self) {
+        let elapsed = (env::block_timestamp() - self.last_price_timestamp) / 1_000_000_000;
+        if elapsed == 0 {
+            return;
+        }
+        if self.a_ticker > 0 && self.b_ticker > 0 {
+            let price_a = mul_div(self.b_ticker, PRICE_PRECISION, self.a_ticker);
+            let price_b = mul_div(self.a_ticker, PRICE_PRECISION, self.b_ticker);
+            self.price_cumulative_a = self
+                .price_cumulative_a
+                .wrapping_add(price_a.wrapping_mul(elapsed as u128));
+            self.price_cumulative_b = self
+                .price_cumulative_b
+                .wrapping_add(price_b.wrapping_mul(elapsed as u128));
+        }
+        // keep the part of a second that was not counted yet
+        self.last_price_timestamp += elapsed * 1_000_000_000;
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Swaps tokens sent with `ft_transfer_call` for the other token of the pool.
+    /// `msg` is the least output (in minimum units) the sender accepts, empty for no limit.
+    /// A rejected swap panics, so the token contract refunds the whole transfer.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_in = env::predecessor_account_id();
+        require!(
+            token_in == self.a_contract_id || token_in == self.b_contract_id,
+            "token_in is not in the pool"
+        );
+        self.check_not_paused().or_panic();
+        if token_in == self.a_contract_id {
+            require!(!self.paused_a_to_b, "swaps from A to B are paused");
+        } else {
+            require!(!self.paused_b_to_a, "swaps from B to A are paused");
+        }
+        self.assert_allowed(&sender_id);
+        self.lock();
+        require!(self.metadata_loaded, "token metadata not loaded");
+        require!(
+            self.registered_a && self.registered_b,
+            "token registration not complete"
+        );
+        self.check_initialized().or_panic();
+        let min_out = if msg.is_empty() {
+            0
+        } else {
+            msg.parse::<Balance>()
+                .expect("msg must be the minimum output amount")
+        };
+        let (amount_in, fee, x_after, y_after, amount_out) =
+            self.calc_swap_units(&token_in, amount.0);
+        self.check_max_trade(&token_in, amount_in);
+        require!(amount_out > 0, "output rounds to zero, increase amount");
+        check_slippage(amount_out, min_out).or_panic();
+        let (a_ticker_after, b_ticker_after, token_out) = if token_in == self.a_contract_id {
+            (x_after, y_after, self.b_contract_id.clone())
+        } else {
+            (y_after, x_after, self.a_contract_id.clone())
+        };
+        self.check_min_reserves(a_ticker_after, b_ticker_after);
+        if let Some(oracle_account) = self.oracle_account.clone() {
+            // the unused amount the callback returns is refunded by the token
+            return self
+                .oracle_price(oracle_account)
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(self.gas_for_swap_output())
+                        .callback_oracle_transfer(
+                            a_ticker_after,
+                            b_ticker_after,
+                            token_out,
+                            sender_id,
+                            amount_in,
+                            amount_out,
+                            fee,
+                        ),
+                )
+                .into();
+        }
+        // the tokens are already here, so the whole amount is used
+        self.send_swap_output(
+            a_ticker_after,
+            b_ticker_after,
+            token_out,
+            sender_id,
+            amount_in,
+            amount_out,
+            fee,
+            None,
+        );
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+/// a * b / c rounded down, without overflowing on the intermediate product
+fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    (U256::from(a) * U256::from(b) / U256::from(c)).as_u128()
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// A swap of `amount_in` against the reserves `x` of the input and `y` of the output with product `k`,
+/// returns (fee, input ticker after, output ticker after, output amount), see `calc_swap_units`.
+/// Fails with `InsufficientLiquidity` if the output reserve can't cover the trade.
+fn swap_units(
+    x: Balance,
+    y: Balance,
+    k: U256,
+    fee_bps: u16,
+    amount_in: Balance,
+) -> Result<(Balance, Balance, Balance, Balance), PoolError> {
+    let fee = amount_in
+        .checked_mul(fee_bps as u128)
+        .expect("amount overflow")
+        / BPS_DENOMINATOR;
+    let x_after = (amount_in - fee).checked_add(x).expect("ticker overflow");
+    let x_after_u256 = U256::from(x_after);
+    let y_after = ((k + x_after_u256 - 1) / x_after_u256)
+        .try_into()
+        .expect("ticker overflow");
+    // only a K above the reserves' product can price the output ticker above the current one
+    let amount_out = y
+        .checked_sub(y_after)
+        .ok_or(PoolError::InsufficientLiquidity)?;
+    Ok((fee, x_after, y_after, amount_out))
+}
+
+/// The amounts of a `transfer_fees` whose transfers failed, 0 for those that went through.
+fn failed_fee_transfers(a_amount: Balance, b_amount: Balance) -> (Balance, Balance) {
+    // the transfers were joined A first, and only for positive amounts
+    let mut results = (0..env::promise_results_count())
+        .map(|i| matches!(env::promise_result(i), PromiseResult::Successful(_)));
+    let a_amount = if a_amount > 0 && !results.next().unwrap_or(false) {
+        a_amount
+    } else {
+        0
+    };
+    let b_amount = if b_amount > 0 && !results.next().unwrap_or(false) {
+        b_amount
+    } else {
+        0
+    };
+    (a_amount, b_amount)
+}
+
+/// `amount` minimum units of a token with `decimals` in whole tokens, e.g. "1.5", for the logs.
+fn format_units(amount: Balance, decimals: u8) -> String {
+    let unit = 10_u128.pow(decimals as u32);
+    let fraction = amount % unit;
+    if fraction == 0 {
+        return (amount / unit).to_string();
+    }
+    let fraction = format!("{:0width$}", fraction, width = decimals as usize);
+    format!("{}.{}", amount / unit, fraction.trim_end_matches('0'))
+}
+
+fn check_amount(amount: Balance) -> Result<(), PoolError> {
+    if amount == 0 {
+        return Err(PoolError::ZeroAmount);
+    }
+    Ok(())
+}
+
+fn check_slippage(amount_out: Balance, min_out: Balance) -> Result<(), PoolError> {
+    if amount_out < min_out {
+        return Err(PoolError::SlippageExceeded);
+    }
+    Ok(())
+}
+
+/// a * b / c rounded up
+fn mul_div_ceil(a: u128, b: u128, c: u128) -> u128 {
+    let product = U256::from(a) * U256::from(b);
+    let c = U256::from(c);
+    ((product + c - 1) / c).as_u128()
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::mock::VmAction;
+    use near_sdk::serde::de::DeserializeOwned;
+    use near_sdk::serde::Deserialize;
+    use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
+    use near_sdk::{serde_json, testing_env};
+
+    use super::*;
+
+    const A_TICKER: u128 = 40000000000000000000000;
+    const B_TICKER: u128 = 300000000000000000000;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    /// deploys the pool with accounts(1) as owner, accounts(2) as token A and accounts(3) as token B
+    /// and fills in the metadata as the get_info callbacks would
+    fn setup() -> (VMContextBuilder, Contract) {
+        setup_with_fee(0)
+    }
+
+    fn setup_with_fee(fee_bps: u16) -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            Some(fee_bps),
+        );
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_get_info(accounts(2), Ok(("A Token Contract".into(), 18)));
+        contract.callback_get_info(accounts(3), Ok(("B Token Contract".into(), 15)));
+        contract.callback_register_amm(accounts(2), Ok(()));
+        contract.callback_register_amm(accounts(3), Ok(()));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        (context, contract)
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct FtDepositArgs {
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        min_out: Balance,
+        referrer: Option<AccountId>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct TransferFromArgs {
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: Balance,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct RegisterAmmArgs {
+        sender_id: AccountId,
+        amount: Balance,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct FlashSwapArgs {
+        token_out: AccountId,
+        amount: Balance,
+        fee: Balance,
+        balance_before: Balance,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct UpdateTickersArgs {
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        amount: Balance,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct SwapOutputArgs {
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+        referrer: Option<AccountId>,
+    }
+
+    /// runs the callback of the output transfer a swap scheduled, with its result
+    fn output_sent(contract: &mut Contract, transfer_result: Result<(), PromiseError>) -> U128 {
+        let args = scheduled_call("callback_swap_output");
+        run_swap_output(contract, args, transfer_result)
+    }
+
+    fn run_swap_output(
+        contract: &mut Contract,
+        args: SwapOutputArgs,
+        transfer_result: Result<(), PromiseError>,
+    ) -> U128 {
+        contract.callback_swap_output(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.referrer,
+            transfer_result,
+        )
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct AddLiquidityArgs {
+        shares: Balance,
+        a_amount: Balance,
+        b_amount: Balance,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct RemoveLiquidityArgs {
+        a_amount: Balance,
+        b_amount: Balance,
+    }
+
+    /// burns `shares` of `account_id` and completes both transfers, leaving the predecessor as accounts(4)
+    fn withdraw_liquidity(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        account_id: AccountId,
+        shares: Balance,
+    ) {
+        testing_env!(context.predecessor_account_id(account_id.clone()).build());
+        contract.remove_liquidity(U128(shares));
+        let args: RemoveLiquidityArgs = scheduled_call("callback_remove_liquidity");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_remove_liquidity(
+            account_id,
+            args.a_amount,
+            args.b_amount,
+            Ok(()),
+            Ok(()),
+        );
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+    }
+
+    /// registers `account_id` for liquidity with the minimum storage deposit
+    fn register(context: &mut VMContextBuilder, contract: &mut Contract, account_id: AccountId) {
+        let deposit = contract.storage_balance_bounds().min.0;
+        testing_env!(context
+            .predecessor_account_id(account_id.clone())
+            .attached_deposit(deposit)
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(0).build());
+    }
+
+    /// the arguments of the last scheduled call to `method`
+    fn scheduled_call<T: DeserializeOwned>(method: &str) -> T {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == method => Some(serde_json::from_slice(&args).unwrap()),
+                _ => None,
+            })
+            .next_back()
+            .unwrap_or_else(|| panic!("no {} scheduled", method))
+    }
+
+    /// the output amount a swap scheduled by deposit_a or deposit_b is going to transfer
+    fn scheduled_swap_output() -> Balance {
+        scheduled_call::<FtDepositArgs>("callback_ft_deposit_balance").amount
+    }
+
+    /// runs the balance query of the swap scheduled by deposit_a or deposit_b, which schedules its transfer_from,
+    /// and returns the swap with the balance of the input token the pool had before
+    fn pull_swap_input(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+    ) -> (FtDepositArgs, Balance) {
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        let balance_before = if args.contract_id == accounts(3) {
+            contract.a_ticker + contract.a_fees
+        } else {
+            contract.b_ticker + contract.b_fees
+        };
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_ft_deposit_balance(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id.clone(),
+            args.receiver_id.clone(),
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            args.referrer.clone(),
+            Ok(U128(balance_before)),
+        );
+        (args, balance_before)
+    }
+
+    /// runs the callbacks of the swap scheduled by deposit_a or deposit_b as if every transfer succeeded
+    /// and returns the output amount
+    fn finish_swap(context: &mut VMContextBuilder, contract: &mut Contract) -> Balance {
+        let (args, balance_before) = pull_swap_input(context, contract);
+        contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            args.referrer,
+            Ok(U128(balance_before + args.amount_in)),
+        );
+        let received = output_sent(contract, Ok(()));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        received.0
+    }
+
+    #[test]
+    fn test_swap_logs_human_amounts() {
+        let (mut context, mut contract) = setup();
+        contract.deposit_a(U128(111), None, u64::MAX, None, None, None);
+        let (args, balance_before) = pull_swap_input(&mut context, &mut contract);
+        contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            args.referrer,
+            Ok(U128(balance_before + args.amount_in)),
+        );
+        output_sent(&mut contract, Ok(()));
+        assert!(get_logs().contains(&format!(
+            "Swapped 111 {} for 830.196205529655206 {} to {}",
+            accounts(2),
+            accounts(3),
+            accounts(4)
+        )));
+    }
+
+    #[test]
+    fn test_format_units() {
+        assert_eq!(format_units(111 * 10_u128.pow(18), 18), "111");
+        assert_eq!(format_units(1_500, 3), "1.5");
+        assert_eq!(format_units(5, 3), "0.005");
+        assert_eq!(format_units(0, 24), "0");
+        assert_eq!(format_units(42, 0), "42");
+    }
+
+    #[test]
+    fn test_deposit_a_within_slippage() {
+        let (_, mut contract) = setup();
+        // 40000 A * 300000 B, swapping 111 A yields 830.196 B
+        contract.deposit_a(
+            U128(111),
+            Some(U128(830_196_205_529_655_206)),
+            u64::MAX,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_deposit_a_within_gas_budget() {
+        let (mut context, mut contract) = setup();
+        // every step runs with only the gas its caller attached, and scheduling more would panic
+        testing_env!(context.prepaid_gas(Gas(185_000_000_000_000)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        // the pool's balance of A, the input token
+        let balance_before = contract.a_ticker + contract.a_fees;
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .prepaid_gas(contract.gas_for_pull_swap_input())
+            .build());
+        contract.callback_ft_deposit_balance(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id.clone(),
+            args.receiver_id.clone(),
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            None,
+            Ok(U128(balance_before)),
+        );
+        testing_env!(context.prepaid_gas(contract.gas_for_swap_output()).build());
+        contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Ok(U128(balance_before + args.amount_in)),
+        );
+        let args = scheduled_call("callback_swap_output");
+        testing_env!(context.prepaid_gas(contract.gas_for_swap_refund()).build());
+        let received = run_swap_output(&mut contract, args, Ok(()));
+        assert_eq!(received.0, 830_196_205_529_655_206);
+    }
+
+    /// the gas attached to the last scheduled call to `method`
+    fn scheduled_gas(method: &str) -> Gas {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name, gas, ..
+                } if function_name == method => Some(gas),
+                _ => None,
+            })
+            .next_back()
+            .unwrap_or_else(|| panic!("no {} scheduled", method))
+    }
+
+    #[test]
+    fn test_deposit_a_with_callback_gas() {
+        let (mut context, mut contract) = setup();
+        let callback_gas = Gas(40_000_000_000_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_callback_gas(callback_gas);
+        assert_eq!(contract.get_config().callback_gas, callback_gas);
+
+        // a token whose transfer_from leaves the default gas short, every step gets the extra gas
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .prepaid_gas(Gas(215_000_000_000_000))
+            .build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        assert_eq!(
+            scheduled_gas("callback_ft_deposit_balance"),
+            contract.gas_for_pull_swap_input()
+        );
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        // the pool's balance of A, the input token
+        let balance_before = contract.a_ticker + contract.a_fees;
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .prepaid_gas(contract.gas_for_pull_swap_input())
+            .build());
+        contract.callback_ft_deposit_balance(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id.clone(),
+            args.receiver_id.clone(),
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            None,
+            Ok(U128(balance_before)),
+        );
+        assert_eq!(
+            scheduled_gas("callback_ft_deposit"),
+            contract.gas_for_swap_output()
+        );
+        testing_env!(context.prepaid_gas(contract.gas_for_swap_output()).build());
+        contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Ok(U128(balance_before + args.amount_in)),
+        );
+        assert_eq!(
+            scheduled_gas("callback_swap_output"),
+            contract.gas_for_swap_refund()
+        );
+        let args = scheduled_call("callback_swap_output");
+        testing_env!(context.prepaid_gas(contract.gas_for_swap_refund()).build());
+        let received = run_swap_output(&mut contract, args, Ok(()));
+        assert_eq!(received.0, 830_196_205_529_655_206);
+    }
+
+    #[test]
+    #[should_panic(expected = "callback_gas must be between 10 and 100 Tgas")]
+    fn test_set_callback_gas_too_high() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_callback_gas(Gas(MAX_CALLBACK_GAS.0 + 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "callback_gas must be between 10 and 100 Tgas")]
+    fn test_set_callback_gas_too_low() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_callback_gas(Gas(GAS_FOR_CALLBACK.0 - 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "slippage exceeded")]
+    fn test_deposit_a_slippage_exceeded() {
+        let (_, mut contract) = setup();
+        contract.deposit_a(
+            U128(111),
+            Some(U128(830_196_205_529_655_207)),
+            u64::MAX,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_swap_route_matches_deposits() {
+        let (_, mut contract) = setup();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let deposit_a: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        let (_, mut contract) = setup();
+        contract.swap_route(accounts(2), U128(111), U128(0));
+        let route_a: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        assert_eq!(route_a.amount_in, deposit_a.amount_in);
+        assert_eq!(route_a.amount, deposit_a.amount);
+        assert_eq!(route_a.contract_id, accounts(3));
+
+        let (_, mut contract) = setup();
+        contract.deposit_b(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let deposit_b: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        let (_, mut contract) = setup();
+        contract.swap_route(accounts(3), U128(111), U128(0));
+        let route_b: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        assert_eq!(route_b.amount_in, deposit_b.amount_in);
+        assert_eq!(route_b.amount, deposit_b.amount);
+        assert_eq!(route_b.contract_id, accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "token_in is not in the pool")]
+    fn test_swap_route_foreign_token() {
+        let (_, mut contract) = setup();
+        contract.swap_route(accounts(5), U128(111), U128(0));
+    }
+
+    #[test]
+    fn test_deposit_default_slippage() {
+        let (mut context, mut contract) = setup();
+        let expected = contract.get_amount_out(accounts(2), U128(111)).0;
+        contract.deposit_a(U128(111), None, u64::MAX, None, None, None);
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        assert_eq!(args.min_out, expected * 9950 / 10_000);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_default_slippage(100);
+        contract.locked = false;
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let expected = contract.get_amount_out(accounts(3), U128(1000)).0;
+        contract.deposit_b(U128(1000), None, u64::MAX, None, None, None);
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        assert_eq!(args.min_out, expected * 9900 / 10_000);
+    }
+
+    #[test]
+    fn test_deposit_explicit_min_out_overrides_default() {
+        let (_, mut contract) = setup();
+        contract.deposit_a(U128(111), Some(U128(5)), u64::MAX, None, None, None);
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        assert_eq!(args.min_out, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "default_slippage_bps must not exceed 10000")]
+    fn test_set_default_slippage_too_high() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_default_slippage(10_001);
+    }
+
+    #[test]
+    fn test_deposit_under_max_trade() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_max_trade_a(Some(U128(111)));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "amount exceeds the max trade size")]
+    fn test_deposit_over_max_trade() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_max_trade_b(Some(U128(10)));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_b(U128(11), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "amount exceeds the max trade size")]
+    fn test_ft_on_transfer_over_max_trade() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_max_trade_a(Some(U128(111)));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(4), U128(111 * 10_u128.pow(18) + 1), "".into());
+    }
+
+    #[test]
+    fn test_deposit_above_min_reserve() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_min_reserves(U128(0), U128(299000));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "trade would take a reserve below its floor")]
+    fn test_deposit_below_min_reserve() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_min_reserves(U128(0), U128(299000));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(200), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "trade would take a reserve below its floor")]
+    fn test_ft_on_transfer_below_min_reserve() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_min_reserves(U128(39990), U128(0));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.ft_on_transfer(accounts(4), U128(100 * 10_u128.pow(15)), "".into());
+    }
+
+    #[test]
+    fn test_swap_of_max_input() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_min_reserves(U128(0), U128(299000));
+        let max_input = contract.get_max_input(accounts(2), None).0;
+        let (_, _, _, b_after, _) = contract.calc_swap_units(&accounts(2), max_input);
+        assert!(b_after >= 299000 * 10_u128.pow(15));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(4), U128(max_input), "".into());
+    }
+
+    #[test]
+    #[should_panic(expected = "trade would take a reserve below its floor")]
+    fn test_swap_over_max_input() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_min_reserves(U128(0), U128(299000));
+        let max_input = contract.get_max_input(accounts(2), None).0;
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(4), U128(max_input + 1), "".into());
+    }
+
+    #[test]
+    fn test_max_input_without_floor() {
+        let (mut context, mut contract) = setup();
+        // by default a swap may take half of the other reserve
+        let max_input = contract.get_max_input(accounts(3), None).0;
+        let (_, _, _, a_after, _) = contract.calc_swap_units(&accounts(3), max_input);
+        assert!(a_after >= A_TICKER / 2);
+        let (_, _, _, a_after, _) = contract.calc_swap_units(&accounts(3), max_input + 1);
+        assert!(a_after < A_TICKER / 2);
+        assert!(contract.get_max_input(accounts(3), Some(1000)).0 < max_input);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_max_trade_b(Some(U128(10)));
+        assert_eq!(
+            contract.get_max_input(accounts(3), None),
+            U128(10 * 10_u128.pow(15))
+        );
+    }
+
+    #[test]
+    fn test_max_trade_removed() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_max_trade_a(Some(U128(1)));
+        contract.set_max_trade_a(None);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "insufficient B liquidity for this trade")]
+    fn test_deposit_a_insufficient_liquidity() {
+        let (_, mut contract) = setup();
+        // out of sync with K, as if the B reserve had been drained
+        contract.b_ticker = 1;
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "insufficient A liquidity for this trade")]
+    fn test_deposit_b_insufficient_liquidity() {
+        let (_, mut contract) = setup();
+        contract.a_ticker = 1;
+        contract.deposit_b(U128(1), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    fn test_check_amount() {
+        assert_eq!(check_amount(0), Err(PoolError::ZeroAmount));
+        assert_eq!(check_amount(1), Ok(()));
+    }
+
+    #[test]
+    fn test_check_slippage() {
+        assert_eq!(check_slippage(99, 100), Err(PoolError::SlippageExceeded));
+        assert_eq!(check_slippage(100, 100), Ok(()));
+    }
+
+    #[test]
+    fn test_check_not_paused() {
+        let (mut context, mut contract) = setup();
+        assert_eq!(contract.check_not_paused(), Ok(()));
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.pause();
+        assert_eq!(contract.check_not_paused(), Err(PoolError::Paused));
+    }
+
+    #[test]
+    fn test_check_initialized_without_metadata() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            None,
+        );
+        // pricing needs both decimals
+        assert_eq!(contract.check_initialized(), Err(PoolError::NotInitialized));
+        assert_eq!(
+            contract.quote_amount_out(&accounts(2), 1),
+            Err(PoolError::NotInitialized)
+        );
+    }
+
+    #[test]
+    fn test_low_reserve_high_decimal_token_is_usable() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        // half an A token with 24 decimals, so the ratio in whole tokens truncates to 0
+        let a_initial = 5 * 10_u128.pow(23);
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(a_initial),
+            U128(B_TICKER),
+            Some(0),
+        );
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_get_info(accounts(2), Ok(("A Token Contract".into(), 24)));
+        contract.callback_get_info(accounts(3), Ok(("B Token Contract".into(), 15)));
+        contract.callback_register_amm(accounts(2), Ok(()));
+        contract.callback_register_amm(accounts(3), Ok(()));
+        assert_eq!(contract.get_ratio(), U128(0));
+        assert_eq!(contract.check_initialized(), Ok(()));
+        // 300000 B per half A
+        assert_eq!(contract.get_spot_price(), (U128(600000), U128(1)));
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_b(U128(3000), None, u64::MAX, None, None, None);
+        let out = finish_swap(&mut context, &mut contract);
+        assert_eq!(out, mul_div(a_initial, 3000, 303000));
+        assert_eq!(contract.get_info().0 .2, a_initial - out);
+    }
+
+    /// Asserts that the reserves price one whole A at `num / den` whole B to within 1e-12.
+    fn assert_spot_price(contract: &Contract, num: Balance, den: Balance) {
+        // A has 18 decimals and B 15
+        let (a_ticker, b_ticker) = contract.get_reserves();
+        let spot = U256::from(b_ticker.0) * U256::from(1_000) * U256::from(den);
+        let target = U256::from(a_ticker.0) * U256::from(num);
+        let diff = if spot > target {
+            spot - target
+        } else {
+            target - spot
+        };
+        assert!(
+            diff <= target / U256::exp10(12),
+            "spot price {:?} is not {}/{}",
+            contract.get_spot_price(),
+            num,
+            den
+        );
+    }
+
+    #[test]
+    fn test_swap_to_price_up() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        // 300000 B / 40000 A = 7.5, a higher price sells B
+        contract.swap_to_price(U128(8), U128(1));
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        assert_eq!(args.contract_id, accounts(2));
+        finish_swap(&mut context, &mut contract);
+        assert_spot_price(&contract, 8, 1);
+    }
+
+    #[test]
+    fn test_swap_to_price_down() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        // a lower price sells A
+        contract.swap_to_price(U128(29), U128(4));
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        assert_eq!(args.contract_id, accounts(3));
+        finish_swap(&mut context, &mut contract);
+        assert_spot_price(&contract, 29, 4);
+        // the fee is charged on the whole input, which includes it
+        assert_eq!(
+            contract.get_fees().0,
+            U128(mul_div(args.amount_in, 30, BPS_DENOMINATOR))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "the spot price is already at the target price")]
+    fn test_swap_to_price_at_target() {
+        let (_, mut contract) = setup();
+        contract.swap_to_price(U128(15), U128(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "target price must be positive")]
+    fn test_swap_to_price_zero() {
+        let (_, mut contract) = setup();
+        contract.swap_to_price(U128(0), U128(1));
+    }
+
+    #[test]
+    fn test_ratio_precision() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        // half an A token against 3 B tokens
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(5 * 10_u128.pow(23)),
+            U128(3 * 10_u128.pow(15)),
+            Some(0),
+        );
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_get_info(accounts(2), Ok(("A Token Contract".into(), 24)));
+        contract.callback_get_info(accounts(3), Ok(("B Token Contract".into(), 15)));
+        assert_eq!(contract.get_ratio(), U128(0));
+        let k = contract.get_k();
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        // 0.5 * 3 = 1.5 with two decimals of each reserve
+        contract.set_ratio_precision(2);
+        assert_eq!(contract.get_ratio(), U128(15_000));
+        assert_eq!(contract.get_config().ratio_precision, 2);
+        // more decimals than B has scale it up instead
+        contract.set_ratio_precision(18);
+        assert_eq!(contract.get_ratio(), U128(15 * 10_u128.pow(35)));
+        // swaps price against K, which the precision doesn't touch
+        assert_eq!(contract.get_k(), k);
+        contract.set_ratio_precision(0);
+        assert_eq!(contract.get_ratio(), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ratio_precision must not exceed 18")]
+    fn test_set_ratio_precision_too_high() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_ratio_precision(MAX_RATIO_PRECISION + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_set_ratio_precision_not_owner() {
+        let (_, mut contract) = setup();
+        contract.set_ratio_precision(2);
+    }
+
+    #[test]
+    fn test_try_calc_swap_units_insufficient_liquidity() {
+        let (_, mut contract) = setup();
+        assert!(contract.try_calc_swap_units(&accounts(2), 111).is_ok());
+        contract.b_ticker = 1;
+        assert_eq!(
+            contract.try_calc_swap_units(&accounts(2), 111),
+            Err(PoolError::InsufficientLiquidity)
+        );
+        assert_eq!(
+            contract.quote_amount_out(&accounts(2), 111),
+            Err(PoolError::InsufficientLiquidity)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "amount must be positive")]
+    fn test_deposit_a_zero_amount() {
+        let (_, mut contract) = setup();
+        contract.deposit_a(U128(0), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "amount must be positive")]
+    fn test_deposit_b_zero_amount() {
+        let (_, mut contract) = setup();
+        contract.deposit_b(U128(0), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "amount must be positive")]
+    fn test_deposit_a_by_owner_zero_amount() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.deposit_a_by_owner(U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "amount must be positive")]
+    fn test_deposit_b_by_owner_zero_amount() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.deposit_b_by_owner(U128(0));
+    }
+
+    #[test]
+    fn test_small_swap_within_price_impact() {
+        let (mut context, mut contract) = setup();
+        // 111 A out of 40000 moves the price by about 0.28%
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, Some(30), None, None);
+        finish_swap(&mut context, &mut contract);
+        contract.deposit_b(U128(100), Some(U128(0)), u64::MAX, Some(10), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "price impact too high")]
+    fn test_large_swap_exceeds_price_impact() {
+        let (_, mut contract) = setup();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, Some(27), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "price impact too high")]
+    fn test_deposit_b_exceeds_price_impact() {
+        let (_, mut contract) = setup();
+        // a tenth of the B reserve costs about 9% of the price
+        contract.deposit_b(U128(30000), Some(U128(0)), u64::MAX, Some(500), None, None);
+    }
+
+    #[test]
+    fn test_partial_deposit_fully_filled() {
+        let (_, mut contract) = setup();
+        contract.deposit_a(
+            U128(111),
+            Some(U128(830_196_205_529_655_206)),
+            u64::MAX,
+            None,
+            Some(true),
+            None,
+        );
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        assert_eq!(args.amount_in, 111 * 10_u128.pow(18));
+        assert_eq!(args.amount, 830_196_205_529_655_206);
+    }
+
+    #[test]
+    fn test_partial_deposit_partially_filled() {
+        let (mut context, mut contract) = setup();
+        // at least 7 B per A: 300000 / (40000 + x) >= 7 holds up to x = 2857
+        contract.deposit_a(
+            U128(10000),
+            Some(U128(70000 * 10_u128.pow(15))),
+            u64::MAX,
+            None,
+            Some(true),
+            None,
+        );
+        let (args, _) = pull_swap_input(&mut context, &mut contract);
+        assert_eq!(args.amount_in, 2857 * 10_u128.pow(18));
+        assert!(args.amount >= 2857 * 7 * 10_u128.pow(15));
+        assert!(get_created_receipts().iter().any(|receipt| {
+            receipt.actions.iter().any(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } => {
+                    function_name == "transfer_from"
+                        && String::from_utf8(args.clone())
+                            .unwrap()
+                            .contains(&format!("\"amount\":{}", 2857 * 10_u128.pow(18)))
+                }
+                _ => false,
+            })
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "slippage exceeded")]
+    fn test_partial_deposit_unfillable() {
+        let (_, mut contract) = setup();
+        // 8 B per A is above the 7.5 spot price, so no part of the order can fill
+        contract.deposit_a(
+            U128(10000),
+            Some(U128(80000 * 10_u128.pow(15))),
+            u64::MAX,
+            None,
+            Some(true),
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "slippage exceeded")]
+    fn test_deposit_b_slippage_exceeded() {
+        let (_, mut contract) = setup();
+        contract.deposit_b(
+            U128(1000),
+            Some(U128(10_u128.pow(30))),
+            u64::MAX,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_deposit_before_deadline() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.block_timestamp(1_000).build());
+        contract.deposit_a(
+            U128(111),
+            Some(U128(0)),
+            1_000 + 120_000_000_000,
+            None,
+            None,
+            None,
+        );
+        finish_swap(&mut context, &mut contract);
+        contract.deposit_b(U128(1), Some(U128(0)), 1_000, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "output rounds to zero, increase amount")]
+    fn test_deposit_b_output_rounds_to_zero() {
+        let (mut context, mut contract) = setup();
+        // a single A is left against 10^19 B, so one B is worth less than a minimum unit of A
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_update_tickers(10_u128.pow(18), 10_u128.pow(34), 0, Ok(()));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_b(U128(1), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "output rounds to zero, increase amount")]
+    fn test_ft_on_transfer_output_rounds_to_zero() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(4), U128(1), "".into());
+    }
+
+    #[test]
+    #[should_panic(expected = "deadline passed")]
+    fn test_deposit_a_deadline_passed() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.block_timestamp(1_000).build());
+        contract.deposit_a(U128(111), Some(U128(0)), 999, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "deadline passed")]
+    fn test_deposit_b_deadline_passed() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.block_timestamp(1_000).build());
+        contract.deposit_b(U128(1), Some(U128(0)), 999, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "contract is paused")]
+    fn test_deposit_a_paused() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.pause();
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    fn test_paused_a_to_b_still_allows_b_to_a() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_paused_a_to_b(true);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_b(U128(1), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "swaps from A to B are paused")]
+    fn test_deposit_a_paused_a_to_b() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_paused_a_to_b(true);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    fn test_deposit_a_allowlisted() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.enable_allowlist();
+        contract.add_to_allowlist(accounts(4));
+        assert!(contract.get_config().allowlist_enabled);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        assert_eq!(
+            finish_swap(&mut context, &mut contract),
+            830_196_205_529_655_206
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "account is not on the allowlist")]
+    fn test_deposit_b_not_allowlisted() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.enable_allowlist();
+        contract.add_to_allowlist(accounts(5));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_b(U128(1), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "account is not on the allowlist")]
+    fn test_ft_on_transfer_not_allowlisted() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.enable_allowlist();
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(4), U128(111 * 10_u128.pow(18)), "".into());
+    }
+
+    #[test]
+    fn test_allowlist_disabled() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.enable_allowlist();
+        contract.add_to_allowlist(accounts(5));
+        contract.remove_from_allowlist(accounts(5));
+        contract.disable_allowlist();
+        assert!(!contract.get_config().allowlist_enabled);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        assert_eq!(
+            finish_swap(&mut context, &mut contract),
+            830_196_205_529_655_206
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "allowlist is not enabled")]
+    fn test_add_to_disabled_allowlist() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.add_to_allowlist(accounts(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "swaps from B to A are paused")]
+    fn test_ft_on_transfer_paused_b_to_a() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_paused_b_to_a(true);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.ft_on_transfer(accounts(4), U128(10_u128.pow(15)), "".into());
+    }
+
+    #[test]
+    fn test_pause_all_and_unpause_all() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.pause_all();
+        assert!(contract.paused_a_to_b && contract.paused_b_to_a);
+        contract.unpause_all();
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "contract is paused")]
+    fn test_add_liquidity_paused() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.pause();
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.add_liquidity(U128(10_u128.pow(18)), U128(10_u128.pow(15)));
+    }
+
+    #[test]
+    fn test_unpause() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.pause();
+        // the owner can still rebalance while paused
+        contract.deposit_a_by_owner(U128(1));
+        let args: UpdateTickersArgs = scheduled_call("callback_update_tickers");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_update_tickers(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.amount,
+            Ok(()),
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.unpause();
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        contract.deposit_b(U128(1), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    fn test_owner_transfer() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.propose_owner(accounts(5));
+        // the proposal alone does not move ownership
+        assert_eq!(contract.get_owner(), accounts(1));
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .build());
+        contract.accept_owner();
+        assert_eq!(contract.get_owner(), accounts(5));
+        contract.pause();
+    }
+
+    #[test]
+    fn test_accept_owner_before_expiry() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(1_000)
+            .attached_deposit(1)
+            .build());
+        contract.propose_owner(accounts(5));
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1_000 + OWNER_PROPOSAL_DURATION - 1)
+            .attached_deposit(1)
+            .build());
+        contract.accept_owner();
+        assert_eq!(contract.get_owner(), accounts(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "the ownership proposal has expired")]
+    fn test_accept_owner_after_expiry() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(1_000)
+            .attached_deposit(1)
+            .build());
+        contract.propose_owner(accounts(5));
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1_000 + OWNER_PROPOSAL_DURATION)
+            .attached_deposit(1)
+            .build());
+        contract.accept_owner();
+    }
+
+    #[test]
+    #[should_panic(expected = "only the pending owner can accept")]
+    fn test_accept_owner_by_wrong_account() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.propose_owner(accounts(5));
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.accept_owner();
+    }
+
+    #[test]
+    #[should_panic(expected = "only the pending owner can accept")]
+    fn test_accept_owner_twice() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.propose_owner(accounts(5));
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .build());
+        contract.accept_owner();
+        contract.accept_owner();
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_pause_without_yocto() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.pause();
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_unpause_with_more_than_yocto() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.pause();
+        testing_env!(context.attached_deposit(2).build());
+        contract.unpause();
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_propose_owner_without_yocto() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.propose_owner(accounts(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_accept_owner_without_yocto() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.propose_owner(accounts(5));
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        contract.accept_owner();
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_pause_by_non_owner() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.attached_deposit(1).build());
+        contract.pause();
+    }
+
+    #[test]
+    #[should_panic(expected = "token metadata not loaded")]
+    fn test_deposit_a_before_metadata_loaded() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            None,
+        );
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "token decimals not loaded")]
+    fn test_deposit_b_by_owner_before_decimals_loaded() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            None,
+        );
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_get_info(accounts(2), Ok(("A Token Contract".into(), 18)));
+        assert_eq!(contract.get_ratio().0, 0);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.deposit_b_by_owner(U128(1));
+    }
+
+    #[test]
+    fn test_metadata_status() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            None,
+        );
+        assert_eq!(contract.get_metadata_status(), (false, false));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_get_info(accounts(3), Ok(("B Token Contract".into(), 15)));
+        assert_eq!(contract.get_metadata_status(), (false, true));
+        contract.callback_get_info(accounts(2), Ok(("A Token Contract".into(), 18)));
+        assert_eq!(contract.get_metadata_status(), (true, true));
+    }
+
+    #[test]
+    fn test_register_with_token_after_failed_registration() {
+        let (mut context, mut contract) = setup();
+        contract.registered_b = false;
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_register_amm(accounts(3), Err(PromiseError::Failed));
+        assert!(!contract.registered_b);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.register_with_token(accounts(3), U128(B_TICKER));
+        let args: RegisterAmmArgs = scheduled_call("register_amm");
+        assert_eq!((args.sender_id, args.amount), (accounts(1), B_TICKER));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_register_amm(accounts(3), Ok(()));
+        assert!(contract.registered_b);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "token registration not complete")]
+    fn test_deposit_before_registration() {
+        let (_, mut contract) = setup();
+        contract.registered_a = false;
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "token is already registered")]
+    fn test_register_with_token_twice() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.register_with_token(accounts(2), U128(A_TICKER));
+    }
+
+    #[test]
+    fn test_refresh_metadata_after_failed_fetch() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            None,
+        );
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_register_amm(accounts(2), Ok(()));
+        contract.callback_register_amm(accounts(3), Ok(()));
+        contract.callback_get_info(accounts(2), Ok(("A Token Contract".into(), 18)));
+        contract.callback_get_info(accounts(3), Err(PromiseError::Failed));
+        assert!(!contract.metadata_loaded);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.refresh_metadata();
+        let fetched: Vec<AccountId> = get_created_receipts()
+            .into_iter()
+            .filter(|receipt| {
+                receipt.actions.iter().any(|action| match action {
+                    VmAction::FunctionCall { function_name, .. } => function_name == "get_info",
+                    _ => false,
+                })
+            })
+            .map(|receipt| receipt.receiver_id)
+            .collect();
+        assert_eq!(fetched, vec![accounts(2), accounts(3)]);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_get_info(accounts(2), Ok(("A Token Contract".into(), 18)));
+        contract.callback_get_info(accounts(3), Ok(("B Token Contract".into(), 15)));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        assert_eq!(scheduled_swap_output(), 827_712_488_550_738_884);
+    }
+
+    #[test]
+    fn test_duplicate_metadata_ignored() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_get_info(accounts(2), Ok(("Other Token".into(), 6)));
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                "Metadata of {} is already loaded, ignoring it",
+                accounts(2)
+            )]
+        );
+        assert_eq!(contract.a_contract_name, "A Token Contract");
+        assert_eq!(contract.decimals(), (18, 15));
+        assert_eq!(contract.get_ratio(), U128(40000 * 300000));
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_refresh_metadata_by_non_owner() {
+        let (_, mut contract) = setup();
+        contract.refresh_metadata();
+    }
+
+    #[test]
+    fn test_two_18_decimal_tokens_with_large_supplies() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        // 10^20 whole tokens on each side, whose product doesn't fit in u128
+        let reserve = 10_u128.pow(38);
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(reserve),
+            U128(reserve),
+            Some(0),
+        );
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_get_info(accounts(2), Ok(("A Token Contract".into(), 18)));
+        contract.callback_get_info(accounts(3), Ok(("B Token Contract".into(), 18)));
+        contract.callback_register_amm(accounts(2), Ok(()));
+        contract.callback_register_amm(accounts(3), Ok(()));
+        assert_eq!(
+            contract.ratio,
+            U256::from(10_u128.pow(20)) * U256::from(10_u128.pow(20))
+        );
+        assert_eq!(contract.get_ratio(), U128(u128::MAX));
+        assert_eq!(contract.get_k(), format!("1{}", "0".repeat(76)));
+        // at this depth 1 A buys 1 B, less the rounding of the B ticker up
+        assert_eq!(
+            contract.get_amount_out(accounts(2), U128(1)),
+            U128(10_u128.pow(18) - 1)
+        );
+    }
+
+    #[test]
+    fn test_set_token_decimals() {
+        let (mut context, mut contract) = setup();
+        assert_eq!(contract.get_ratio().0, 40000 * 300000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_token_decimals(accounts(2), 17);
+        assert_eq!(contract.a_contract_decimals, Some(17));
+        assert_eq!(contract.get_ratio().0, 400000 * 300000);
+    }
+
+    #[test]
+    #[should_panic(expected = "token is not in the pool")]
+    fn test_set_token_decimals_foreign_token() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_token_decimals(accounts(5), 17);
+    }
+
+    #[test]
+    #[should_panic(expected = "amount overflow")]
+    fn test_deposit_a_amount_overflow() {
+        let (_, mut contract) = setup();
+        contract.deposit_a(
+            U128(u128::MAX / 10_u128.pow(17)),
+            Some(U128(0)),
+            u64::MAX,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ticker overflow")]
+    fn test_deposit_b_ticker_overflow() {
+        let (_, mut contract) = setup();
+        contract.deposit_b(
+            U128(u128::MAX / 10_u128.pow(15)),
+            Some(U128(0)),
+            u64::MAX,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_withdraw_a_by_owner() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_a_by_owner(U128(10000));
+        let args: UpdateTickersArgs = scheduled_call("callback_update_tickers");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_update_tickers(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.amount,
+            Ok(()),
+        );
+        assert_eq!(contract.a_ticker, A_TICKER - 10000 * 10_u128.pow(18));
+        assert_eq!(contract.b_ticker, B_TICKER);
+        assert_eq!(contract.get_ratio(), U128(30000 * 300000));
+    }
+
+    #[test]
+    #[should_panic(expected = "liquidity providers still hold shares")]
+    fn test_withdraw_by_owner_with_providers() {
+        let (mut context, mut contract) = setup();
+        provide_liquidity(&mut context, &mut contract, accounts(4), 1);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_b_by_owner(U128(1));
+    }
+
+    #[test]
+    fn test_withdraw_b_by_owner_transfer_failed() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_b_by_owner(U128(1));
+        let args: UpdateTickersArgs = scheduled_call("callback_update_tickers");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_update_tickers(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.amount,
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(contract.b_ticker, B_TICKER);
+        assert_eq!(contract.get_ratio(), U128(40000 * 300000));
+    }
+
+    #[test]
+    #[should_panic(expected = "insufficient B liquidity for this withdrawal")]
+    fn test_withdraw_b_by_owner_more_than_reserve() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_b_by_owner(U128(300001));
+    }
+
+    /// runs the callback of a rebalance with the given outcomes of its transfers, in the order they were joined
+    fn finish_rebalance(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        a_target: Balance,
+        b_target: Balance,
+        transfers: Vec<bool>,
+    ) -> (U128, U128) {
+        transfers_done(context, transfers);
+        contract.callback_rebalance(a_target, b_target)
+    }
+
+    /// sets up a callback of the contract on itself after transfers with the given outcomes
+    fn transfers_done(context: &mut VMContextBuilder, transfers: Vec<bool>) {
+        let results = transfers
+            .into_iter()
+            .map(|ok| {
+                if ok {
+                    PromiseResult::Successful(vec![])
+                } else {
+                    PromiseResult::Failed
+                }
+            })
+            .collect();
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            results,
+        );
+    }
+
+    #[test]
+    fn test_rebalance() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        let (a_target, b_target) = (A_TICKER + 10_u128.pow(18), B_TICKER - 10_u128.pow(15));
+        contract.rebalance(U128(a_target), U128(b_target));
+        let transfers: Vec<(AccountId, TransferFromArgs)> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| {
+                let receiver_id = receipt.receiver_id;
+                receipt
+                    .actions
+                    .into_iter()
+                    .filter_map(move |action| match action {
+                        VmAction::FunctionCall {
+                            function_name,
+                            args,
+                            ..
+                        } if function_name == "transfer_from" => {
+                            Some((receiver_id.clone(), serde_json::from_slice(&args).unwrap()))
+                        }
+                        _ => None,
+                    })
+            })
+            .collect();
+        // the owner sends in 1 A and gets back 1 B
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(transfers[0].0, accounts(2));
+        assert_eq!(
+            (
+                &transfers[0].1.sender_id,
+                &transfers[0].1.receiver_id,
+                transfers[0].1.amount
+            ),
+            (&accounts(1), &accounts(0), 10_u128.pow(18))
+        );
+        assert_eq!(transfers[1].0, accounts(3));
+        assert_eq!(
+            (
+                &transfers[1].1.sender_id,
+                &transfers[1].1.receiver_id,
+                transfers[1].1.amount
+            ),
+            (&accounts(0), &accounts(1), 10_u128.pow(15))
+        );
+
+        let reserves = finish_rebalance(
+            &mut context,
+            &mut contract,
+            a_target,
+            b_target,
+            vec![true, true],
+        );
+        assert_eq!(reserves, (U128(a_target), U128(b_target)));
+        assert_eq!(contract.get_ratio(), U128(40001 * 299999));
+        assert_eq!(
+            contract.get_k(),
+            (U256::from(a_target) * U256::from(b_target)).to_string()
+        );
+        // one sync for both reserves
+        assert_eq!(
+            get_logs()
+                .iter()
+                .filter(|log| log.contains(r#""event":"sync""#))
+                .count(),
+            1
+        );
+        assert!(!contract.locked);
+    }
+
+    #[test]
+    fn test_rebalance_failed_leg() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        let (a_target, b_target) = (A_TICKER + 10_u128.pow(18), B_TICKER + 10_u128.pow(15));
+        contract.rebalance(U128(a_target), U128(b_target));
+        // the owner had no B to send
+        let reserves = finish_rebalance(
+            &mut context,
+            &mut contract,
+            a_target,
+            b_target,
+            vec![true, false],
+        );
+        assert_eq!(reserves, (U128(a_target), U128(B_TICKER)));
+        assert_eq!(
+            get_logs()[0],
+            "Transfer of B failed, the B reserve is not rebalanced"
+        );
+    }
+
+    #[test]
+    fn test_rebalance_one_reserve() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        let b_target = B_TICKER - 10_u128.pow(15);
+        contract.rebalance(U128(A_TICKER), U128(b_target));
+        let reserves =
+            finish_rebalance(&mut context, &mut contract, A_TICKER, b_target, vec![true]);
+        assert_eq!(reserves, (U128(A_TICKER), U128(b_target)));
+    }
+
+    #[test]
+    fn test_close_pool() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.pause();
+        contract.close_pool();
+        let transfers: Vec<TransferFromArgs> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "transfer_from" => {
+                    Some(serde_json::from_slice(&args).unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            transfers
+                .iter()
+                .map(|args| (&args.sender_id, &args.receiver_id, args.amount))
+                .collect::<Vec<_>>(),
+            vec![
+                (&accounts(0), &accounts(1), A_TICKER),
+                (&accounts(0), &accounts(1), B_TICKER)
+            ]
+        );
+
+        transfers_done(&mut context, vec![true, true]);
+        assert_eq!(contract.callback_close_pool(), (U128(0), U128(0)));
+        assert_eq!(contract.get_total_shares(), U128(0));
+        assert_eq!(contract.get_share_balance(accounts(1)), U128(0));
+        assert_eq!(contract.get_ratio(), U128(0));
+        assert!(!contract.locked);
+    }
+
+    #[test]
+    #[should_panic(expected = "pool is empty")]
+    fn test_swap_in_empty_pool() {
+        let (mut context, mut contract) = setup();
+        assert_eq!(contract.check_initialized(), Ok(()));
+        // a reserve drained by a close whose other transfer failed
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.pause();
+        contract.close_pool();
+        transfers_done(&mut context, vec![true, false]);
+        assert_eq!(contract.callback_close_pool(), (U128(0), U128(B_TICKER)));
+        assert_eq!(contract.check_initialized(), Err(PoolError::EmptyPool));
+        assert_eq!(
+            contract.quote_amount_out(&accounts(3), 1),
+            Err(PoolError::EmptyPool)
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.unpause();
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "pool is empty")]
+    fn test_deposit_b_in_empty_pool() {
+        let (_, mut contract) = setup();
+        contract.a_ticker = 0;
+        contract.calc_ratio();
+        contract.deposit_b(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "liquidity providers still hold shares")]
+    fn test_close_pool_with_providers() {
+        let (mut context, mut contract) = setup();
+        contract.shares.insert(&accounts(4), &5);
+        contract.total_shares += 5;
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.pause();
+        contract.close_pool();
+    }
+
+    #[test]
+    #[should_panic(expected = "liquidity providers still hold shares")]
+    fn test_rebalance_with_providers() {
+        let (mut context, mut contract) = setup();
+        contract.shares.insert(&accounts(4), &5);
+        contract.total_shares += 5;
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.rebalance(U128(A_TICKER / 2), U128(B_TICKER / 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "the pool must be paused to close it")]
+    fn test_close_pool_unpaused() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.close_pool();
+    }
+
+    #[test]
+    #[should_panic(expected = "reserves are already at the targets")]
+    fn test_rebalance_to_current_reserves() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.rebalance(U128(A_TICKER), U128(B_TICKER));
+    }
+
+    #[test]
+    #[should_panic(expected = "amount overflow")]
+    fn test_deposit_a_by_owner_amount_overflow() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.deposit_a_by_owner(U128(u128::MAX));
+    }
+
+    #[test]
+    #[should_panic(expected = "ticker overflow")]
+    fn test_deposit_b_by_owner_ticker_overflow() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.deposit_b_by_owner(U128(u128::MAX / 10_u128.pow(15)));
+    }
+
+    #[test]
+    fn test_callback_ft_deposit_inbound_failed() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let before = contract.get_info();
+        let result = contract.callback_ft_deposit(
+            1,
+            1,
+            accounts(3),
+            accounts(4),
+            1,
+            1,
+            1,
+            0,
+            A_TICKER,
+            None,
+            Ok(U128(A_TICKER)),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        assert_eq!(contract.get_info(), before);
+        assert!(!get_logs().iter().any(|log| log.starts_with("EVENT_JSON:")));
+    }
+
+    #[test]
+    fn test_callback_ft_deposit_balance_query_failed() {
+        let (mut context, mut contract) = setup();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let (args, balance_before) = pull_swap_input(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let before = contract.get_info();
+        let result = contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Err(PromiseError::Failed),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        // the input may have arrived, so nothing is sent or refunded and the lock stays for the owner
+        assert!(get_created_receipts().is_empty());
+        assert!(contract.locked);
+        assert_eq!(contract.get_info(), before);
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                "Balance query failed, the inbound transfer of {} for {} is unconfirmed and the pool stays locked",
+                args.amount_in,
+                accounts(4)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_swap_priced_on_amount_received() {
+        let (mut context, mut contract) = setup();
+        let quote = contract.get_amount_out(accounts(2), U128(110)).0;
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let (args, balance_before) = pull_swap_input(&mut context, &mut contract);
+        // the token keeps 1 A of the transfer
+        let received = args.amount_in - 10_u128.pow(18);
+        contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Ok(U128(balance_before + received)),
+        );
+        let output = output_sent(&mut contract, Ok(())).0;
+        assert_eq!(output, quote);
+        assert_eq!(contract.a_ticker, A_TICKER + 110 * 10_u128.pow(18));
+        assert_eq!(contract.b_ticker, B_TICKER - output);
+    }
+
+    #[test]
+    fn test_swap_refunded_when_amount_received_misses_min_out() {
+        let (mut context, mut contract) = setup();
+        contract.deposit_a(
+            U128(111),
+            Some(U128(830_196_205_529_655_206)),
+            u64::MAX,
+            None,
+            None,
+            None,
+        );
+        let (args, balance_before) = pull_swap_input(&mut context, &mut contract);
+        let received = args.amount_in - 10_u128.pow(18);
+        let result = contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Ok(U128(balance_before + received)),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        let refund: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(
+            (refund.sender_id, refund.receiver_id, refund.amount),
+            (accounts(0), accounts(4), received)
+        );
+        assert_eq!(contract.a_ticker, A_TICKER);
+        assert!(!contract.locked);
+    }
+
+    #[test]
+    fn test_owner_deposit_emits_sync_event() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.deposit_a_by_owner(U128(1));
+        let args: UpdateTickersArgs = scheduled_call("callback_update_tickers");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_update_tickers(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.amount,
+            Ok(()),
+        );
+        let event = format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"sync","data":[{{"a_ticker":"{}","b_ticker":"{}"}}]}}"#,
+            contract.a_ticker, contract.b_ticker
+        );
+        assert_eq!(contract.a_ticker, A_TICKER + 10_u128.pow(18));
+        assert_eq!(get_logs(), vec![event]);
+    }
+
+    #[test]
+    fn test_circuit_breaker_pauses_on_large_change() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_breaker(1000);
+        // 10000 A on top of 40000 A moves the reserve by 25%
+        contract.deposit_a_by_owner(U128(10000));
+        let args: UpdateTickersArgs = scheduled_call("callback_update_tickers");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_update_tickers(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.amount,
+            Ok(()),
+        );
+        let event = format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"circuit_breaker_tripped","data":[{{"a_ticker":"{}","b_ticker":"{}","a_ticker_after":"{}","b_ticker_after":"{}"}}]}}"#,
+            A_TICKER,
+            B_TICKER,
+            A_TICKER + 10000 * 10_u128.pow(18),
+            B_TICKER
+        );
+        assert_eq!(get_logs(), vec![event]);
+        assert!(contract.paused && !contract.locked);
+        assert_eq!(contract.get_reserves(), (U128(A_TICKER), U128(B_TICKER)));
+    }
+
+    #[test]
+    fn test_circuit_breaker_allows_small_change() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_breaker(1000);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        assert!(!contract.paused);
+        assert_eq!(contract.a_ticker, A_TICKER + 111 * 10_u128.pow(18));
+    }
+
+    #[test]
+    fn test_circuit_breaker_refunds_the_swap() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_breaker(1000);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        // 10000 A on top of 40000 A moves the reserve by 25%
+        contract.deposit_a(U128(10000), Some(U128(0)), u64::MAX, None, None, None);
+        let (args, balance_before) = pull_swap_input(&mut context, &mut contract);
+        let result = contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Ok(U128(balance_before + args.amount_in)),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        // the input goes back and no output is sent
+        let refund: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(
+            (refund.sender_id, refund.receiver_id, refund.amount),
+            (accounts(0), accounts(4), args.amount_in)
+        );
+        assert!(!get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .any(|action| matches!(action, VmAction::FunctionCall { function_name, .. } if function_name == "callback_swap_output")));
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains(r#""event":"circuit_breaker_tripped""#)));
+        assert!(contract.paused && !contract.locked);
+        assert_eq!((contract.a_ticker, contract.b_ticker), (A_TICKER, B_TICKER));
+        assert_eq!(contract.get_volume(), (U128(0), U128(0)));
+    }
+
+    #[test]
+    fn test_new_emits_pool_created_event() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            None,
+        );
+        let event = format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"pool_created","data":[{{"owner_id":"{}","a_contract_id":"{}","b_contract_id":"{}","a_initial":"{}","b_initial":"{}"}}]}}"#,
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            A_TICKER,
+            B_TICKER
+        );
+        assert_eq!(get_logs(), vec![event]);
+    }
+
+    #[test]
+    fn test_swap_emits_event() {
+        let (mut context, mut contract) = setup();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let (args, balance_before) = pull_swap_input(&mut context, &mut contract);
+        contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Ok(U128(balance_before + args.amount_in)),
+        );
+        // nothing is reported before the output was sent
+        assert!(get_logs().is_empty());
+        output_sent(&mut contract, Ok(()));
+        let event = format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"swap","data":[{{"account_id":"{}","token_in":"{}","amount_in":"{}","token_out":"{}","amount_out":"{}"}}]}}"#,
+            accounts(4),
+            accounts(2),
+            111 * 10_u128.pow(18),
+            accounts(3),
+            830_196_205_529_655_206_u128
+        );
+        assert!(get_logs().contains(&event));
+    }
+
+    /// lends 1 A to the approved accounts(5) from a pool with a 0.30% fee,
+    /// up to the balance check of the repayment
+    fn start_flash_swap() -> (VMContextBuilder, Contract, FlashSwapArgs) {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_flash_receiver(accounts(5), true);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.flash_swap(accounts(2), U128(10_u128.pow(18)), accounts(5), "".into());
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_flash_swap_lend(
+            accounts(2),
+            10_u128.pow(18),
+            accounts(5),
+            "".into(),
+            Ok(U128(A_TICKER)),
+        );
+        let args: FlashSwapArgs = scheduled_call("callback_flash_swap");
+        (context, contract, args)
+    }
+
+    #[test]
+    fn test_flash_swap_repaid() {
+        let (_, mut contract, args) = start_flash_swap();
+        assert_eq!(args.fee, 3 * 10_u128.pow(15));
+        assert_eq!(args.balance_before, A_TICKER);
+        let repaid = contract.callback_flash_swap(
+            args.token_out,
+            args.amount,
+            args.fee,
+            args.balance_before,
+            Ok(U128(A_TICKER + 3 * 10_u128.pow(15))),
+        );
+        assert!(repaid);
+        assert_eq!(contract.get_fees(), (U128(3 * 10_u128.pow(15)), U128(0)));
+        assert_eq!(contract.a_ticker, A_TICKER);
+        assert!(!contract.paused && !contract.locked);
+    }
+
+    #[test]
+    fn test_flash_swap_under_repaid() {
+        let (_, mut contract, args) = start_flash_swap();
+        // only 10 units of the loan came back
+        let repaid = contract.callback_flash_swap(
+            args.token_out,
+            args.amount,
+            args.fee,
+            args.balance_before,
+            Ok(U128(A_TICKER - 10_u128.pow(18) + 10)),
+        );
+        assert!(!repaid);
+        assert_eq!(contract.get_fees(), (U128(0), U128(0)));
+        assert_eq!(contract.a_ticker, A_TICKER - 10_u128.pow(18) + 10);
+        assert!(contract.paused && !contract.locked);
+    }
+
+    #[test]
+    #[should_panic(expected = "receiver is not approved for flash swaps")]
+    fn test_flash_swap_unapproved_receiver() {
+        let (_, mut contract) = setup();
+        contract.flash_swap(accounts(2), U128(10_u128.pow(18)), accounts(5), "".into());
+    }
+
+    #[test]
+    fn test_volume_counts_swap_inputs() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        assert_eq!(contract.get_volume(), (U128(0), U128(0)));
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        contract.deposit_b(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        contract.deposit_a(U128(5), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        assert_eq!(
+            contract.get_volume(),
+            (U128(116 * 10_u128.pow(18)), U128(1000 * 10_u128.pow(15)))
+        );
+
+        // an owner deposit is not swap volume
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.deposit_b_by_owner(U128(7));
+        assert_eq!(
+            contract.get_volume(),
+            (U128(116 * 10_u128.pow(18)), U128(1000 * 10_u128.pow(15)))
+        );
+    }
+
+    #[test]
+    fn test_recent_trades_newest_last() {
+        let (mut context, mut contract) = setup();
+        assert!(contract.get_recent_trades(10).is_empty());
+        for amount in [1, 2, 3] {
+            contract.deposit_a(U128(amount), Some(U128(0)), u64::MAX, None, None, None);
+            finish_swap(&mut context, &mut contract);
+        }
+        let trades = contract.get_recent_trades(2);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].amount_in, U128(2 * 10_u128.pow(18)));
+        assert_eq!(trades[1].amount_in, U128(3 * 10_u128.pow(18)));
+        assert_eq!(trades[1].token_in, accounts(2));
+        assert_eq!(trades[1].account_id, accounts(4));
+    }
+
+    #[test]
+    fn test_recent_trades_evict_oldest() {
+        let (mut context, mut contract) = setup();
+        for amount in 1..=TRADE_HISTORY_SIZE as u128 + 3 {
+            contract.deposit_a(U128(amount), Some(U128(0)), u64::MAX, None, None, None);
+            finish_swap(&mut context, &mut contract);
+        }
+        assert_eq!(contract.trades.len(), TRADE_HISTORY_SIZE);
+        let trades = contract.get_recent_trades(100);
+        assert_eq!(trades.len() as u64, TRADE_HISTORY_SIZE);
+        let amounts: Vec<u128> = trades
+            .iter()
+            .map(|trade| trade.amount_in.0 / 10_u128.pow(18))
+            .collect();
+        assert_eq!(
+            amounts,
+            (4..=TRADE_HISTORY_SIZE as u128 + 3).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant call")]
+    fn test_reentrant_deposit() {
+        let (_, mut contract) = setup();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        // a token contract calling back before the swap's callbacks ran would see stale tickers
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    fn test_failed_output_transfer_refunds_the_input() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let (args, balance_before) = pull_swap_input(&mut context, &mut contract);
+        contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Ok(U128(balance_before + args.amount_in)),
+        );
+        let output: SwapOutputArgs = scheduled_call("callback_swap_output");
+        // the refund and its callback fit into the gas of callback_swap_output
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .prepaid_gas(contract.gas_for_swap_refund())
+            .build());
+        assert_eq!(
+            run_swap_output(&mut contract, output, Err(PromiseError::Failed)),
+            U128(0)
+        );
+        let refund: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(
+            (refund.sender_id, refund.receiver_id, refund.amount),
+            (accounts(0), accounts(4), args.amount_in)
+        );
+        assert_eq!(scheduled_gas("callback_swap_refund"), contract.callback_gas);
+        // the pool is left as before the swap
+        assert_eq!((contract.a_ticker, contract.b_ticker), (A_TICKER, B_TICKER));
+        assert_eq!(contract.get_fees(), (U128(0), U128(0)));
+        assert_eq!(contract.get_volume(), (U128(0), U128(0)));
+        assert!(contract.get_recent_trades(10).is_empty());
+        assert!(!get_logs()
+            .iter()
+            .any(|log| log.contains(r#""event":"swap""#)));
+        assert!(!contract.locked);
+    }
+
+    #[test]
+    fn test_failed_swap_refund_is_logged() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_swap_refund(accounts(2), accounts(4), 111, Ok(()));
+        assert!(get_logs().is_empty());
+        contract.callback_swap_refund(accounts(2), accounts(4), 111, Err(PromiseError::Failed));
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                "Refund of 111 {} to {} failed, the tokens are held by the pool",
+                accounts(2),
+                accounts(4)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_failed_swaps_release_the_lock() {
+        let (mut context, mut contract) = setup();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let (args, balance_before) = pull_swap_input(&mut context, &mut contract);
+        contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Ok(U128(balance_before)),
+        );
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_ft_deposit_balance(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            None,
+            Err(PromiseError::Failed),
+        );
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_b(U128(1), Some(U128(0)), u64::MAX, None, None, None);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_update_tickers(1, 1, 1, Err(PromiseError::Failed));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+    }
+
+    #[test]
+    fn test_callback_update_tickers() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let ratio = contract.get_ratio().0;
+        assert_eq!(
+            contract.callback_update_tickers(1, 1, 5, Err(PromiseError::Failed)),
+            U128(0)
+        );
+        assert_eq!(contract.get_ratio().0, ratio);
+        assert_eq!(contract.get_info().0 .2, A_TICKER);
+        assert_eq!(contract.get_info().1 .2, B_TICKER);
+
+        assert_eq!(
+            contract.callback_update_tickers(A_TICKER * 2, B_TICKER, 5, Ok(())),
+            U128(5)
+        );
+        assert_eq!(contract.get_info().0 .2, A_TICKER * 2);
+        assert_eq!(contract.get_ratio().0, ratio * 2);
+    }
+
+    #[test]
+    fn test_swap_returns_output_amount() {
+        let (mut context, mut contract) = setup();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let b_amount = 830_196_205_529_655_206;
+        assert_eq!(finish_swap(&mut context, &mut contract), b_amount);
+        assert_eq!(contract.get_info().0 .2, A_TICKER + 111 * 10_u128.pow(18));
+        assert_eq!(contract.get_info().1 .2, B_TICKER - b_amount);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_swaps_both_directions() {
+        let (mut context, mut contract) = setup();
+        let a_amount = 111 * 10_u128.pow(18);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let result = contract.ft_on_transfer(accounts(4), U128(a_amount), "0".into());
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        let args: SwapOutputArgs = scheduled_call("callback_swap_output");
+        assert_eq!(args.amount, 830_196_205_529_655_206);
+        assert_eq!(args.a_ticker_after, A_TICKER + a_amount);
+        assert_eq!(args.b_ticker_after, B_TICKER - args.amount);
+        let b_amount = args.amount;
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        run_swap_output(&mut contract, args, Ok(()));
+
+        // sending the B back returns the A less what rounding kept in the pool
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let result = contract.ft_on_transfer(accounts(4), U128(b_amount), "".into());
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        let args: SwapOutputArgs = scheduled_call("callback_swap_output");
+        assert_eq!(args.amount, a_amount - 108);
+        assert_eq!(args.a_ticker_after, A_TICKER + a_amount - args.amount);
+    }
+
+    #[test]
+    #[should_panic(expected = "slippage exceeded")]
+    fn test_ft_on_transfer_slippage_exceeded() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(
+            accounts(4),
+            U128(111 * 10_u128.pow(18)),
+            "830196205529655207".into(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "msg must be the minimum output amount")]
+    fn test_ft_on_transfer_invalid_msg() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.ft_on_transfer(accounts(4), U128(1), "swap".into());
+    }
+
+    #[test]
+    #[should_panic(expected = "token_in is not in the pool")]
+    fn test_ft_on_transfer_from_foreign_token() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.ft_on_transfer(accounts(4), U128(1), "".into());
+    }
+
+    #[test]
+    fn test_new_with_custom_reserves() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(500 * 10_u128.pow(18)),
+            U128(2000 * 10_u128.pow(15)),
+            None,
+        );
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_get_info(accounts(2), Ok(("A Token Contract".into(), 18)));
+        contract.callback_get_info(accounts(3), Ok(("B Token Contract".into(), 15)));
+        assert_eq!(contract.get_ratio().0, 500 * 2000);
+        assert_eq!(contract.get_spot_price(), (U128(4), U128(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "initial reserves must be positive")]
+    fn test_new_without_reserves() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(0),
+            U128(B_TICKER),
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "tokens must differ")]
+    fn test_new_with_identical_tokens() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(2),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_spot_price() {
+        let (mut context, mut contract) = setup();
+        // 300000 B for 40000 A is 7.5 B per A
+        assert_eq!(contract.get_spot_price(), (U128(15), U128(2)));
+
+        // 1 A against 3 B with the wider decimals on B
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_update_tickers(10_u128.pow(18), 3 * 10_u128.pow(24), 0, Ok(()));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_token_decimals(accounts(3), 24);
+        assert_eq!(contract.get_spot_price(), (U128(3), U128(1)));
+    }
+
+    #[test]
+    fn test_price_cumulative() {
+        let (mut context, mut contract) = setup();
+        let price_a = mul_div(B_TICKER, PRICE_PRECISION, A_TICKER);
+        testing_env!(context.block_timestamp(10_000_000_000).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let (cumulative_a, _, timestamp) = contract.get_price_cumulative();
+        assert_eq!(cumulative_a.0, price_a * 10);
+        assert_eq!(timestamp, 10_000_000_000);
+
+        // the price after the first swap counts for the next 20.5 seconds, of which 20 are summed
+        let (a_reserve, b_reserve) = (contract.get_info().0 .2, contract.get_info().1 .2);
+        let next_price_a = mul_div(b_reserve, PRICE_PRECISION, a_reserve);
+        let next_price_b = mul_div(a_reserve, PRICE_PRECISION, b_reserve);
+        testing_env!(context.block_timestamp(30_500_000_000).build());
+        contract.deposit_b(U128(1), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let (cumulative_a, cumulative_b, timestamp) = contract.get_price_cumulative();
+        assert_eq!(cumulative_a.0, price_a * 10 + next_price_a * 20);
+        assert_eq!(
+            cumulative_b.0,
+            mul_div(A_TICKER, PRICE_PRECISION, B_TICKER) * 10 + next_price_b * 20
+        );
+        assert_eq!(timestamp, 30_000_000_000);
+    }
+
+    #[test]
+    fn test_poke_without_trades() {
+        let (mut context, mut contract) = setup();
+        let price_a = mul_div(B_TICKER, PRICE_PRECISION, A_TICKER);
+        let price_b = mul_div(A_TICKER, PRICE_PRECISION, B_TICKER);
+        testing_env!(context.block_timestamp(15_000_000_000).build());
+        contract.poke();
+        assert_eq!(
+            contract.get_price_cumulative(),
+            (U128(price_a * 15), U128(price_b * 15), 15_000_000_000)
+        );
+        // within the same second there is nothing to add
+        testing_env!(context.block_timestamp(15_900_000_000).build());
+        contract.poke();
+        assert_eq!(contract.get_price_cumulative().0, U128(price_a * 15));
+        assert_eq!(contract.get_reserves(), (U128(A_TICKER), U128(B_TICKER)));
+    }
+
+    #[test]
+    fn test_get_k_never_decreases_with_fees() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        assert_eq!(
+            contract.get_k(),
+            (U256::from(A_TICKER) * U256::from(B_TICKER)).to_string()
+        );
+        let mut k = U256::from_dec_str(&contract.get_k()).unwrap();
+        for amount in [111, 5, 3000, 1, 250] {
+            contract.deposit_a(U128(amount), Some(U128(0)), u64::MAX, None, None, None);
+            finish_swap(&mut context, &mut contract);
+            let next_k = U256::from_dec_str(&contract.get_k()).unwrap();
+            assert!(next_k >= k);
+            k = next_k;
+            contract.deposit_b(U128(amount * 7), Some(U128(0)), u64::MAX, None, None, None);
+            finish_swap(&mut context, &mut contract);
+            let next_k = U256::from_dec_str(&contract.get_k()).unwrap();
+            assert!(next_k >= k);
+            k = next_k;
+        }
+    }
+
+    #[test]
+    fn test_round_trips_never_decrease_k() {
+        let (mut context, mut contract) = setup();
+        let mut k = contract.k;
+        for _ in 0..1000 {
+            contract.deposit_a(U128(1), Some(U128(0)), u64::MAX, None, None, None);
+            finish_swap(&mut context, &mut contract);
+            assert!(contract.k >= k);
+            k = contract.k;
+            contract.deposit_b(U128(7), Some(U128(0)), u64::MAX, None, None, None);
+            finish_swap(&mut context, &mut contract);
+            assert!(contract.k >= k);
+            k = contract.k;
+        }
+        assert!(k >= U256::from(A_TICKER) * U256::from(B_TICKER));
+    }
+
+    #[test]
+    fn test_get_amount_in() {
+        for fee_bps in [0, 30, 1000] {
+            let (_, contract) = setup_with_fee(fee_bps);
+            for (token_in, token_out, amount_out) in [
+                (accounts(2), accounts(3), 830_196_205_529_655_206),
+                (accounts(2), accounts(3), 1),
+                (accounts(3), accounts(2), 10_u128.pow(18)),
+                (accounts(3), accounts(2), A_TICKER / 2),
+            ] {
+                let amount_in = contract.get_amount_in(token_out, U128(amount_out)).0;
+                assert!(contract.get_amount_out(token_in.clone(), U128(amount_in)).0 >= amount_out);
+                // a whole token less falls short
+                assert!(contract.get_amount_out(token_in, U128(amount_in - 1)).0 < amount_out);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "amount_out must be less than the reserve")]
+    fn test_get_amount_in_whole_reserve() {
+        let (_, contract) = setup();
+        contract.get_amount_in(accounts(3), U128(B_TICKER));
+    }
+
+    #[test]
+    fn test_get_amount_out_matches_swaps() {
+        for amount in [1, 111, 5000, 39999] {
+            let (_, mut contract) = setup();
+            let quote = contract.get_amount_out(accounts(2), U128(amount)).0;
+            contract.deposit_a(U128(amount), Some(U128(0)), u64::MAX, None, None, None);
+            assert_eq!(scheduled_swap_output(), quote);
+
+            let (_, mut contract) = setup();
+            let quote = contract.get_amount_out(accounts(3), U128(amount)).0;
+            contract.deposit_b(U128(amount), Some(U128(0)), u64::MAX, None, None, None);
+            assert_eq!(scheduled_swap_output(), quote);
+        }
+    }
+
+    #[test]
+    fn test_get_amount_out_matches_transfers() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        // a chain of swaps in both directions, so the quotes are checked against moved reserves and fees
+        let mut seed: u64 = 0x2545_f491;
+        for i in 0..60 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let amount = (seed >> 33) as u128 % 20_000 + 1;
+            let (quote, received) = if i % 2 == 0 {
+                let quote = contract.get_amount_out(accounts(2), U128(amount)).0;
+                contract.deposit_a(U128(amount), Some(U128(0)), u64::MAX, None, None, None);
+                (quote, finish_swap(&mut context, &mut contract))
+            } else {
+                let quote = contract.get_amount_out(accounts(3), U128(amount)).0;
+                contract.deposit_b(U128(amount), Some(U128(0)), u64::MAX, None, None, None);
+                (quote, finish_swap(&mut context, &mut contract))
+            };
+            assert_eq!(received, quote, "swap {} of {} tokens", i, amount);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "token_in is not in the pool")]
+    fn test_get_amount_out_unknown_token() {
+        let (_, contract) = setup();
+        contract.get_amount_out(accounts(5), U128(1));
+    }
+
+    #[test]
+    fn test_get_amounts_out() {
+        let (_, contract) = setup_with_fee(30);
+        let amounts: Vec<U128> = [0, 1, 111, 5000, 39999].into_iter().map(U128).collect();
+        for token_in in [accounts(2), accounts(3)] {
+            let quotes = contract.get_amounts_out(token_in.clone(), amounts.clone());
+            assert_eq!(quotes.len(), amounts.len());
+            for (amount, quote) in amounts.iter().zip(quotes) {
+                assert_eq!(quote, contract.get_amount_out(token_in.clone(), *amount));
+            }
+        }
+        assert!(contract.get_amounts_out(accounts(2), vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_execution_price_worsens_with_size() {
+        let (_, contract) = setup_with_fee(30);
+        let mut last: Option<(U128, U128)> = None;
+        for amount in [1, 10, 111, 1000, 5000, 20000] {
+            let (input, output) = contract.get_execution_price(accounts(3), U128(amount));
+            assert_eq!(gcd(input.0, output.0), 1);
+            if let Some((last_input, last_output)) = last {
+                // more A per B than for the smaller trade
+                assert!(
+                    U256::from(input.0) * U256::from(last_output.0)
+                        > U256::from(last_input.0) * U256::from(output.0)
+                );
+            }
+            last = Some((input, output));
+        }
+    }
+
+    #[test]
+    fn test_execution_price_of_get_amount_out() {
+        let (_, contract) = setup();
+        let (input, output) = contract.get_execution_price(accounts(2), U128(111));
+        let amount_out = contract.get_amount_out(accounts(2), U128(111)).0;
+        assert_eq!(
+            U256::from(input.0) * U256::from(amount_out),
+            U256::from(111 * 10_u128.pow(18)) * U256::from(output.0)
+        );
+    }
+
+    #[test]
+    fn test_round_trip_quote_without_fee() {
+        let (_, contract) = setup();
+        // only the rounding of the output tickers up is lost
+        assert_eq!(
+            contract.round_trip_quote(accounts(2), U128(111)),
+            U128(110_999_999_999_999_999_892)
+        );
+    }
+
+    #[test]
+    fn test_round_trip_quote_with_fee() {
+        let (_, contract) = setup_with_fee(30);
+        let amount_in = 111 * 10_u128.pow(18);
+        let returned = contract.round_trip_quote(accounts(2), U128(111)).0;
+        assert_eq!(returned, 110_335_912_264_125_166_571);
+        // the loss is about the 0.30% fee charged twice, 1 - 0.997^2,
+        // slightly less since the first fee isn't in the reserves the second swap prices against
+        let compounded_fee = amount_in - amount_in * 9970 * 9970 / 10_000 / 10_000;
+        let loss = amount_in - returned;
+        assert!(loss <= compounded_fee);
+        assert!(loss * 1000 > compounded_fee * 998);
+        // the same holds from the B side
+        assert!(contract.round_trip_quote(accounts(3), U128(111)).0 < 111 * 10_u128.pow(15));
+    }
+
+    #[test]
+    fn test_simulate_swap() {
+        let (_, contract) = setup_with_fee(30);
+        let quote = contract.simulate_swap(accounts(2), U128(111));
+        assert_eq!(quote.amount_out, U128(827_712_488_550_738_884));
+        // 300000 B / 40000 A in minimum units, times 10^12
+        assert_eq!(quote.price_before, U128(7_500_000_000));
+        // (300000 B - 827.71 B) / (40000 A + 110.667 A)
+        assert_eq!(quote.price_after, U128(7_458_671_467));
+        // 110.667 A buy 830.0025 B at the spot price
+        assert_eq!(quote.price_impact_bps, 27);
+        assert_eq!(quote.fee_amount, U128(333 * 10_u128.pow(15)));
+        assert_eq!(contract.get_info().0 .2, A_TICKER);
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct BalanceOfArgs {
+        account_id: AccountId,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct BalanceLimitedOutputArgs {
+        token_in: AccountId,
+    }
+
+    #[test]
+    fn test_get_balance_limited_output() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        contract.get_balance_limited_output(accounts(2), accounts(4));
+        assert_eq!(
+            scheduled_call::<BalanceOfArgs>("ft_balance_of").account_id,
+            accounts(4)
+        );
+        assert_eq!(
+            scheduled_call::<BalanceLimitedOutputArgs>("callback_balance_limited_output").token_in,
+            accounts(2)
+        );
+
+        // 111 A, all of which the pool can pull
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let balance = U128(111 * 10_u128.pow(18));
+        assert_eq!(
+            contract.callback_balance_limited_output(accounts(2), Ok(balance)),
+            contract.get_amount_out(accounts(2), U128(111))
+        );
+        assert_eq!(
+            contract.callback_balance_limited_output(accounts(2), Ok(U128(0))),
+            U128(0)
+        );
+        // a max trade size below the balance caps the input
+        contract.max_trade_a = Some(100);
+        assert_eq!(
+            contract.callback_balance_limited_output(accounts(2), Ok(balance)),
+            contract.get_amount_out(accounts(2), U128(100))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "balance query failed")]
+    fn test_get_balance_limited_output_failed_query() {
+        let (mut context, contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_balance_limited_output(accounts(3), Err(PromiseError::Failed));
+    }
+
+    #[test]
+    fn test_get_owner_balances() {
+        let (mut context, contract) = setup();
+        contract.get_owner_balances();
+        let queries: Vec<(AccountId, String)> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| {
+                let receiver_id = receipt.receiver_id;
+                receipt
+                    .actions
+                    .into_iter()
+                    .filter_map(move |action| match action {
+                        VmAction::FunctionCall {
+                            function_name,
+                            args,
+                            ..
+                        } if function_name == "ft_balance_of" => {
+                            Some((receiver_id.clone(), String::from_utf8(args).unwrap()))
+                        }
+                        _ => None,
+                    })
+            })
+            .collect();
+        let args = format!("{{\"account_id\":\"{}\"}}", accounts(1));
+        assert_eq!(
+            queries,
+            vec![(accounts(2), args.clone()), (accounts(3), args)]
+        );
+        // the owner started with 50000 A and 400000 B and moved the initial reserves
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let balances = contract.callback_owner_balances(
+            Ok(U128(50000 * 10_u128.pow(18) - A_TICKER)),
+            Ok(U128(400000 * 10_u128.pow(15) - B_TICKER)),
+        );
+        assert_eq!(
+            balances,
+            (
+                U128(10000 * 10_u128.pow(18)),
+                U128(100000 * 10_u128.pow(15))
+            )
+        );
+    }
+
+    #[test]
+    fn test_migrate_from_first_release() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        env::state_write(&migration::OldContract {
+            owner_id: accounts(1),
+            ratio: 40000 * 300000,
+            a_ticker: A_TICKER,
+            a_contract_id: accounts(2),
+            a_contract_name: "A Token Contract".into(),
+            a_contract_decimals: 18,
+            b_ticker: B_TICKER,
+            b_contract_id: accounts(3),
+            b_contract_name: "B Token Contract".into(),
+            b_contract_decimals: 15,
+        });
+        let contract = Contract::migrate();
+        assert_eq!(contract.get_owner(), accounts(1));
+        assert_eq!(contract.get_reserves(), (U128(A_TICKER), U128(B_TICKER)));
+        assert_eq!(contract.get_ratio(), U128(40000 * 300000));
+        assert_eq!(contract.decimals(), (18, 15));
+        assert!(contract.metadata_loaded && contract.registered_a && contract.registered_b);
+        assert_eq!(
+            contract.get_k(),
+            (U256::from(A_TICKER) * U256::from(B_TICKER)).to_string()
+        );
+        let config = contract.get_config();
+        assert_eq!(config.fee_bps, 0);
+        assert!(!config.paused);
+        assert_eq!(config.fee_recipient, accounts(1));
+        assert_eq!(contract.get_fees(), (U128(0), U128(0)));
+        assert_eq!(
+            contract.get_share_balance(accounts(1)),
+            contract.get_total_shares()
+        );
+        assert!(contract.get_recent_trades(10).is_empty());
+        assert_eq!(contract.get_version(), "0.2.0");
+        assert_eq!(get_logs(), vec!["Migrated the state to version 0.2.0"]);
+    }
+
+    #[test]
+    fn test_created_at() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(1_234).build());
+        let contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            None,
+        );
+        assert_eq!(contract.get_created_at(), 1_234);
+        // the field is part of the state, so later calls read it back
+        env::state_write(&contract);
+        testing_env!(context.block_timestamp(5_678).build());
+        let contract: Contract = env::state_read().unwrap();
+        assert_eq!(contract.get_created_at(), 1_234);
+    }
+
+    #[test]
+    fn test_created_at_of_migrated_pool() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(9_999).build());
+        env::state_write(&migration::OldContract {
+            owner_id: accounts(1),
+            ratio: 40000 * 300000,
+            a_ticker: A_TICKER,
+            a_contract_id: accounts(2),
+            a_contract_name: "A Token Contract".into(),
+            a_contract_decimals: 18,
+            b_ticker: B_TICKER,
+            b_contract_id: accounts(3),
+            b_contract_name: "B Token Contract".into(),
+            b_contract_decimals: 15,
+        });
+        assert_eq!(Contract::migrate().get_created_at(), 9_999);
+    }
+
+    #[test]
+    fn test_last_update_advances_after_swap() {
+        let (mut context, mut contract) = setup();
+        assert_eq!(contract.get_last_update(), 0);
+        testing_env!(context.block_index(42).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        assert_eq!(contract.get_last_update(), 0);
+        finish_swap(&mut context, &mut contract);
+        assert_eq!(contract.get_last_update(), 42);
+    }
+
+    #[test]
+    fn test_storage_keys_are_distinct() {
+        let prefixes: Vec<Vec<u8>> = [
+            StorageKey::Shares,
+            StorageKey::FlashReceivers,
+            StorageKey::Trades,
+            StorageKey::Allowlist,
+            StorageKey::ReferralFees,
+            StorageKey::RewardDebts,
+        ]
+        .iter()
+        .map(|key| key.try_to_vec().unwrap())
+        .collect();
+        assert_eq!(prefixes, (0..6).map(|i| vec![i]).collect::<Vec<_>>());
+
+        let (mut context, mut contract) = setup();
+        register(&mut context, &mut contract, accounts(4));
+        contract.referral_fees.insert(&accounts(4), &(7, 9));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_flash_receiver(accounts(4), true);
+        contract.enable_allowlist();
+        assert_eq!(contract.get_share_balance(accounts(4)), U128(0));
+        assert_eq!(contract.get_referral_fees(accounts(4)), (U128(7), U128(9)));
+        assert!(!contract.allowlist.as_ref().unwrap().contains(&accounts(4)));
+        contract.add_to_allowlist(accounts(5));
+        assert!(!contract.flash_receivers.contains(&accounts(5)));
+    }
+
+    #[test]
+    fn test_get_version() {
+        let (_, contract) = setup();
+        assert_eq!(contract.get_version(), "0.2.0");
+    }
+
+    #[test]
+    fn test_get_config() {
+        let (mut context, mut contract) = setup();
+        let config = contract.get_config();
+        assert_eq!(config.owner_id, accounts(1));
+        assert_eq!(config.fee_bps, 0);
+        assert!(!config.paused && !config.paused_a_to_b && !config.paused_b_to_a);
+        assert_eq!((config.max_trade_a, config.max_trade_b), (None, None));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_fee(25);
+        contract.pause();
+        contract.set_paused_b_to_a(true);
+        contract.set_max_trade_a(Some(U128(500)));
+        contract.propose_owner(accounts(5));
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .build());
+        contract.accept_owner();
+        let config = contract.get_config();
+        assert_eq!(config.owner_id, accounts(5));
+        assert_eq!(config.fee_bps, 25);
+        assert!(config.paused && !config.paused_a_to_b && config.paused_b_to_a);
+        assert_eq!(
+            (config.max_trade_a, config.max_trade_b),
+            (Some(U128(500)), None)
+        );
+    }
+
+    #[test]
+    fn test_get_pool_health() {
+        let (mut context, mut contract) = setup();
+        let health = contract.get_pool_health();
+        assert!(health.initialized && health.healthy && !health.paused && !health.locked);
+
+        // a swap in flight is normal traffic
+        contract.deposit_a(U128(111), None, u64::MAX, None, None, None);
+        let health = contract.get_pool_health();
+        assert!(health.locked && !health.lock_stale && health.healthy);
+        finish_swap(&mut context, &mut contract);
+        assert!(contract.get_pool_health().healthy);
+    }
+
+    #[test]
+    fn test_get_pool_health_stale_lock() {
+        let (mut context, mut contract) = setup();
+        contract.deposit_a(U128(111), None, u64::MAX, None, None, None);
+        let locked_at = contract.locked_at;
+        testing_env!(context.block_index(locked_at + STALE_LOCK_BLOCKS).build());
+        assert!(contract.get_pool_health().healthy);
+        // the callbacks never came back
+        testing_env!(context
+            .block_index(locked_at + STALE_LOCK_BLOCKS + 1)
+            .build());
+        let health = contract.get_pool_health();
+        assert!(health.locked && health.lock_stale && !health.healthy);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.force_unlock();
+        assert!(contract.get_pool_health().healthy);
+    }
+
+    #[test]
+    fn test_get_pool_health_paused() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_breaker(1000);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        // trips the circuit breaker, which pauses the pool
+        contract.deposit_a(U128(10000), Some(U128(0)), u64::MAX, None, None, None);
+        let (args, balance_before) = pull_swap_input(&mut context, &mut contract);
+        contract.callback_ft_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            balance_before,
+            None,
+            Ok(U128(balance_before + args.amount_in)),
+        );
+        let health = contract.get_pool_health();
+        assert!(health.paused && !health.locked && !health.healthy);
+    }
+
+    #[test]
+    fn test_get_pool_health_uninitialized() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            Some(0),
+        );
+        let health = contract.get_pool_health();
+        assert!(!health.initialized && !health.healthy);
+    }
+
+    #[test]
+    fn test_get_tokens() {
+        let (_, contract) = setup();
+        assert_eq!(contract.get_tokens(), (accounts(2), accounts(3)));
+    }
+
+    #[test]
+    fn test_get_reserves() {
+        let (_, contract) = setup();
+        // both reserves are far above 2^53, the largest integer a JS number holds exactly
+        assert_eq!(
+            serde_json::to_string(&contract.get_reserves()).unwrap(),
+            "[\"40000000000000000000000\",\"300000000000000000000\"]"
+        );
+    }
+
+    #[test]
+    fn test_get_reserves_human() {
+        let (_, mut contract) = setup();
+        assert_eq!(
+            contract.get_reserves_human(),
+            (
+                U128(A_TICKER / 10_u128.pow(18)),
+                U128(B_TICKER / 10_u128.pow(15))
+            )
+        );
+        assert_eq!(contract.get_reserves_human(), (U128(40000), U128(300000)));
+        // fractions of a token are cut off
+        contract.a_ticker += 10_u128.pow(18) - 1;
+        assert_eq!(contract.get_reserves_human().0, U128(40000));
+    }
+
+    #[test]
+    fn test_get_pool_info() {
+        let (_, contract) = setup();
+        assert_eq!(
+            serde_json::to_string(&contract.get_pool_info()).unwrap(),
+            format!(
+                "{{\"a_account\":\"{}\",\"a_symbol\":\"A Token Contract\",\"a_reserve\":\"{}\",\"a_decimals\":18,\
+                 \"b_account\":\"{}\",\"b_symbol\":\"B Token Contract\",\"b_reserve\":\"{}\",\"b_decimals\":15,\
+                 \"ratio\":\"{}\"}}",
+                accounts(2),
+                A_TICKER,
+                accounts(3),
+                B_TICKER,
+                40000_u128 * 300000
+            )
+        );
+    }
+
+    #[test]
+    fn test_u128_json_round_trip() {
+        let (mut context, mut contract) = setup();
+        let amount = 2_u128.pow(53) + 1;
+        let parsed: U128 = serde_json::from_str(&format!("\"{}\"", amount)).unwrap();
+        assert_eq!(parsed.0, amount);
+
+        // 1 A against 2^53 + 1 B keeps a ratio above 2^53
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_update_tickers(10_u128.pow(18), amount * 10_u128.pow(15), 0, Ok(()));
+        assert_eq!(
+            serde_json::to_string(&contract.get_ratio()).unwrap(),
+            "\"9007199254740993\""
+        );
+    }
+
+    #[test]
+    fn test_swap_fee() {
+        let (_, contract) = setup_with_fee(0);
+        assert_eq!(
+            contract.get_amount_out(accounts(2), U128(111)).0,
+            830_196_205_529_655_206
+        );
+        // 0.30% of 111 A leaves 110.667 A to price the swap
+        let (_, mut contract) = setup_with_fee(30);
+        assert_eq!(
+            contract.get_amount_out(accounts(2), U128(111)).0,
+            827_712_488_550_738_884
+        );
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        assert_eq!(scheduled_swap_output(), 827_712_488_550_738_884);
+    }
+
+    #[test]
+    fn test_skim_donation() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.skim();
+        assert!(contract.locked);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        // someone sent 5 B straight to the pool
+        let skimmed = contract.callback_skim(
+            Ok(U128(A_TICKER)),
+            Ok(U128(B_TICKER + 5_000_000_000_000_000)),
+        );
+        assert_eq!(skimmed, (U128(0), U128(5_000_000_000_000_000)));
+        assert_eq!(
+            contract.get_reserves(),
+            (U128(A_TICKER), U128(B_TICKER + 5_000_000_000_000_000))
+        );
+        assert_eq!(contract.get_ratio(), U128(40000 * 300005));
+        assert_eq!(
+            contract.get_k(),
+            (U256::from(A_TICKER) * U256::from(B_TICKER + 5_000_000_000_000_000)).to_string()
+        );
+        assert!(!contract.locked);
+    }
+
+    #[test]
+    fn test_skim_leaves_fees() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let (a_fees, _) = contract.get_fees();
+        let a_ticker = contract.a_ticker;
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.skim();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let skimmed =
+            contract.callback_skim(Ok(U128(a_ticker + a_fees.0)), Ok(U128(contract.b_ticker)));
+        assert_eq!(skimmed, (U128(0), U128(0)));
+        assert_eq!(contract.a_ticker, a_ticker);
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_skim_not_owner() {
+        let (_, mut contract) = setup();
+        contract.skim();
+    }
+
+    #[test]
+    fn test_sync_repairs_ticker() {
+        let (mut context, mut contract) = setup();
+        // a ticker left stale, as by a failed callback
+        contract.a_ticker = 1;
+        contract.calc_ratio();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.sync();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let tickers = contract.callback_sync(Ok(U128(A_TICKER)), Ok(U128(B_TICKER)));
+        assert_eq!(tickers, (U128(A_TICKER), U128(B_TICKER)));
+        assert_eq!(contract.get_reserves(), (U128(A_TICKER), U128(B_TICKER)));
+        assert_eq!(contract.get_ratio(), U128(40000 * 300000));
+        assert_eq!(
+            contract.get_k(),
+            (U256::from(A_TICKER) * U256::from(B_TICKER)).to_string()
+        );
+        assert!(!contract.locked);
+    }
+
+    #[test]
+    fn test_force_unlock_after_lost_callback() {
+        let (mut context, mut contract) = setup();
+        // a swap whose callbacks never came back
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        assert!(contract.locked);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.force_unlock();
+        assert!(!contract.locked);
+        assert_eq!(get_logs(), vec!["The owner released the lock"]);
+        // and the owner can reconcile the tickers again
+        contract.sync();
+        assert!(contract.locked);
+    }
+
+    #[test]
+    #[should_panic(expected = "the pool is not locked")]
+    fn test_force_unlock_unlocked() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.force_unlock();
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_force_unlock_by_non_owner() {
+        let (mut context, mut contract) = setup();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        testing_env!(context.attached_deposit(1).build());
+        contract.force_unlock();
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_sync_by_non_owner() {
+        let (_, mut contract) = setup();
+        contract.sync();
+    }
+
+    /// the pool of setup, guarded by an oracle with a 1% tolerance, the spot price is 7.5 B per A
+    fn setup_with_oracle() -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_oracle(Some(accounts(5)), 100);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        (context, contract)
+    }
+
+    /// runs the oracle check of the swap scheduled by deposit_a or deposit_b with `price`
+    fn oracle_swap(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        price: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        let args: FtDepositArgs = scheduled_call("callback_oracle_swap");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_oracle_swap(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            args.min_out,
+            args.referrer,
+            price,
+        )
+    }
+
+    #[test]
+    fn test_oracle_allows_swap() {
+        let (mut context, mut contract) = setup_with_oracle();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let receipt = get_created_receipts().remove(0);
+        assert_eq!(receipt.receiver_id, accounts(5));
+        let expected = contract.get_amount_out(accounts(2), U128(111)).0;
+        // 0.5% above the spot price
+        oracle_swap(&mut context, &mut contract, Ok(U128(7_537_500_000_000)));
+        assert_eq!(scheduled_swap_output(), expected);
+        assert_eq!(finish_swap(&mut context, &mut contract), expected);
+        assert_eq!(contract.get_info().1 .2, B_TICKER - expected);
+    }
+
+    #[test]
+    fn test_oracle_blocks_swap() {
+        let (mut context, mut contract) = setup_with_oracle();
+        contract.deposit_b(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        // 2% below the spot price
+        let result = oracle_swap(&mut context, &mut contract, Ok(U128(7_350_000_000_000)));
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        assert!(get_created_receipts().is_empty());
+        assert!(!contract.locked);
+        assert_eq!(contract.get_info().0 .2, A_TICKER);
+    }
+
+    #[test]
+    fn test_oracle_unavailable_fails_closed() {
+        let (mut context, mut contract) = setup_with_oracle();
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let result = oracle_swap(&mut context, &mut contract, Err(PromiseError::Failed));
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        assert!(!contract.locked);
+        assert_eq!(get_logs()[0], "Oracle price unavailable");
+    }
+
+    #[test]
+    fn test_oracle_guards_ft_on_transfer() {
+        let (mut context, mut contract) = setup_with_oracle();
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let amount = 111 * 10_u128.pow(18);
+        contract.ft_on_transfer(accounts(4), U128(amount), "".into());
+        let args: PendingSwapArgs = scheduled_call("callback_oracle_transfer");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let refund = contract.callback_oracle_transfer(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id.clone(),
+            args.receiver_id.clone(),
+            args.amount_in,
+            args.amount,
+            args.fee,
+            Ok(U128(8_000_000_000_000)),
+        );
+        assert_eq!(refund, U128(amount));
+        assert!(!contract.locked);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(4), U128(amount), "".into());
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let refund = contract.callback_oracle_transfer(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            Ok(U128(7_500_000_000_000)),
+        );
+        assert_eq!(refund, U128(0));
+        let transfer: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(
+            (transfer.receiver_id, transfer.amount),
+            (accounts(4), args.amount)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "deposit_near is unavailable while the oracle guard is set")]
+    fn test_deposit_near_with_oracle() {
+        let (mut context, mut contract) = setup_with_oracle();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_wrap_contract(Some(accounts(2)));
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.deposit_near(U128(0));
+    }
+
+    /// the arguments of callback_near_deposit and callback_oracle_transfer, whose input has arrived
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct PendingSwapArgs {
+        a_ticker_after: Balance,
+        b_ticker_after: Balance,
+        contract_id: AccountId,
+        receiver_id: AccountId,
+        amount_in: Balance,
+        amount: Balance,
+        fee: Balance,
+    }
+
+    #[test]
+    fn test_deposit_near() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_wrap_contract(Some(accounts(2)));
+        let deposit = 111 * 10_u128.pow(18);
+        let expected = contract.get_amount_out(accounts(2), U128(111)).0;
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(deposit)
+            .build());
+        contract.deposit_near(U128(expected));
+        let receipt = get_created_receipts().remove(0);
+        assert_eq!(receipt.receiver_id, accounts(2));
+        assert!(matches!(
+            &receipt.actions[0],
+            VmAction::FunctionCall { function_name, deposit: attached, .. }
+                if function_name == "near_deposit" && *attached == deposit
+        ));
+        let args: PendingSwapArgs = scheduled_call("callback_near_deposit");
+        assert_eq!((args.amount_in, args.amount), (deposit, expected));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(0)
+            .build());
+        contract.callback_near_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            Ok(()),
+        );
+        let transfer: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(
+            (transfer.sender_id, transfer.receiver_id, transfer.amount),
+            (accounts(0), accounts(4), expected)
+        );
+        output_sent(&mut contract, Ok(()));
+        assert_eq!(contract.get_info().0 .2, args.a_ticker_after);
+        assert_eq!(contract.get_info().1 .2, B_TICKER - expected);
+        assert_eq!(contract.get_fees().0 .0, args.fee);
+    }
+
+    #[test]
+    fn test_deposit_near_wrap_failed() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_wrap_contract(Some(accounts(3)));
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10_u128.pow(15))
+            .build());
+        contract.deposit_near(U128(0));
+        let args: PendingSwapArgs = scheduled_call("callback_near_deposit");
+        assert_eq!(args.contract_id, accounts(2));
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(0)
+            .build());
+        let result = contract.callback_near_deposit(
+            args.a_ticker_after,
+            args.b_ticker_after,
+            args.contract_id,
+            args.receiver_id,
+            args.amount_in,
+            args.amount,
+            args.fee,
+            Err(PromiseError::Failed),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        let refund = get_created_receipts().pop().unwrap();
+        assert_eq!(refund.receiver_id, accounts(4));
+        assert!(matches!(
+            refund.actions[0],
+            VmAction::Transfer { deposit } if deposit == 10_u128.pow(15)
+        ));
+        assert!(!contract.locked);
+        assert_eq!(contract.get_info().1 .2, B_TICKER);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrapped NEAR is not configured")]
+    fn test_deposit_near_not_configured() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.attached_deposit(1).build());
+        contract.deposit_near(U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "token is not in the pool")]
+    fn test_set_wrap_contract_outside_pool() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_wrap_contract(Some(accounts(5)));
+    }
+
+    #[test]
+    fn test_rescue_token() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.rescue_token(accounts(5), U128(42), accounts(4));
+        let receipt = get_created_receipts().pop().unwrap();
+        assert_eq!(receipt.receiver_id, accounts(5));
+        match &receipt.actions[0] {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                deposit,
+                ..
+            } => {
+                assert_eq!(function_name, "ft_transfer");
+                assert_eq!(*deposit, 1);
+                assert_eq!(
+                    String::from_utf8(args.clone()).unwrap(),
+                    format!(
+                        "{{\"receiver_id\":\"{}\",\"amount\":\"42\",\"memo\":null}}",
+                        accounts(4)
+                    )
+                );
+            }
+            action => panic!("unexpected {:?}", action),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "pool tokens can't be rescued")]
+    fn test_rescue_pool_token() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.rescue_token(accounts(3), U128(42), accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_rescue_token_by_non_owner() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.attached_deposit(1).build());
+        contract.rescue_token(accounts(5), U128(42), accounts(4));
+    }
+
+    #[test]
+    fn test_default_fee() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = Contract::new(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            U128(A_TICKER),
+            U128(B_TICKER),
+            None,
+        );
+        assert_eq!(contract.get_fee(), 30);
+        contract.set_fee(1000);
+        assert_eq!(contract.get_fee(), 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "fee_bps must not exceed 1000")]
+    fn test_set_fee_too_high() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_fee(1001);
+    }
+
+    #[test]
+    fn test_fee_ramp() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_fee_ramp(100, 30, 10_000_000_000, 20_000_000_000);
+        for (timestamp, fee_bps) in [
+            (5_000_000_000, 100),
+            (10_000_000_000, 100),
+            (15_000_000_000, 65),
+            (20_000_000_000, 30),
+            (30_000_000_000, 30),
+        ] {
+            testing_env!(context.block_timestamp(timestamp).build());
+            assert_eq!(contract.get_fee(), fee_bps, "at {}", timestamp);
+        }
+
+        // a swap at the midpoint pays the fee of that moment
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(15_000_000_000)
+            .build());
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        let args: FtDepositArgs = scheduled_call("callback_ft_deposit_balance");
+        assert_eq!(args.fee, 1000 * 10_u128.pow(18) * 65 / 10_000);
+    }
+
+    #[test]
+    fn test_set_fee_ends_ramp() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_fee_ramp(100, 30, 10_000_000_000, 20_000_000_000);
+        contract.set_fee(50);
+        testing_env!(context.block_timestamp(15_000_000_000).build());
+        assert_eq!(contract.get_fee(), 50);
+    }
+
+    #[test]
+    #[should_panic(expected = "the ramp must end after it starts")]
+    fn test_fee_ramp_backwards() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_fee_ramp(100, 30, 20_000_000_000, 10_000_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_set_fee_not_owner() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.attached_deposit(1).build());
+        contract.set_fee(10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_set_fee_ramp_without_yocto() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_fee_ramp(0, 100, 0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_withdraw_by_owner_without_yocto() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.withdraw_a_by_owner(U128(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_rescue_token_without_yocto() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.rescue_token(accounts(5), U128(42), accounts(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_set_fee_without_yocto() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_fee(10);
+    }
+
+    #[test]
+    fn test_fees_accumulate() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        let a_decimal = 10_u128.pow(18);
+        let b_decimal = 10_u128.pow(15);
+        for amount in [111, 250, 1000] {
+            contract.deposit_a(U128(amount), Some(U128(0)), u64::MAX, None, None, None);
+            finish_swap(&mut context, &mut contract);
+        }
+        let a_in = (111 + 250 + 1000) * a_decimal;
+        let a_fees = a_in * 30 / 10_000;
+        assert_eq!(contract.get_fees(), (U128(a_fees), U128(0)));
+        // the fees are kept out of the reserves used for pricing
+        assert_eq!(contract.get_info().0 .2, A_TICKER + a_in - a_fees);
+
+        contract.deposit_b(U128(2000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let b_fees = 2000 * b_decimal * 30 / 10_000;
+        assert_eq!(contract.get_fees(), (U128(a_fees), U128(b_fees)));
+    }
+
+    #[test]
+    fn test_protocol_fee_split() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_protocol_fee(2500, accounts(5));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let fee = 111 * 10_u128.pow(18) * 30 / 10_000;
+        let (protocol_fees, fees) = (contract.get_protocol_fees().0 .0, contract.get_fees().0 .0);
+        assert_eq!(protocol_fees, fee / 4);
+        assert_eq!(protocol_fees + fees, fee);
+        assert_eq!(contract.get_config().fee_recipient, accounts(5));
+
+        // anyone may send them, they only go to the recipient
+        contract.collect_protocol_fees(accounts(2));
+        let args: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(
+            (args.sender_id, args.receiver_id, args.amount),
+            (accounts(0), accounts(5), protocol_fees)
+        );
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_collect_protocol_fees(accounts(2), protocol_fees, Ok(()));
+        assert_eq!(contract.get_protocol_fees(), (U128(0), U128(0)));
+        assert_eq!(contract.get_fees().0 .0, fees);
+    }
+
+    #[test]
+    fn test_collect_protocol_fees_transfer_failed() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_protocol_fee(2500, accounts(5));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let protocol_fees = contract.get_protocol_fees().0 .0;
+
+        // the fees are taken out as soon as the transfer is scheduled
+        contract.collect_protocol_fees(accounts(2));
+        assert_eq!(contract.get_protocol_fees(), (U128(0), U128(0)));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_collect_protocol_fees(
+            accounts(2),
+            protocol_fees,
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(contract.get_protocol_fees().0 .0, protocol_fees);
+    }
+
+    #[test]
+    #[should_panic(expected = "no fees to collect")]
+    fn test_collect_protocol_fees_twice() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_protocol_fee(2500, accounts(5));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        contract.collect_protocol_fees(accounts(2));
+        contract.collect_protocol_fees(accounts(2));
+    }
+
+    #[test]
+    fn test_set_fee_recipient() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_protocol_fee(2500, accounts(1));
+        contract.set_fee_recipient(accounts(5));
+        assert_eq!(contract.get_config().fee_recipient, accounts(5));
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"fee_recipient_changed","data":[{{"old_recipient":"{}","new_recipient":"{}"}}]}}"#,
+                accounts(1),
+                accounts(5)
+            )]
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        contract.collect_protocol_fees(accounts(2));
+        let args: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(args.receiver_id, accounts(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "the pool can't be the fee recipient")]
+    fn test_set_fee_recipient_to_pool() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_fee_recipient(accounts(0));
+    }
+
+    #[test]
+    fn test_get_fee_amount() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_protocol_fee(2500, accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let quote = contract.get_fee_amount(accounts(2), U128(111));
+        assert_eq!(
+            quote.0,
+            mul_div_ceil(111 * 10_u128.pow(18), 30, BPS_DENOMINATOR)
+        );
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        assert_eq!(
+            scheduled_call::<FtDepositArgs>("callback_ft_deposit_balance").fee,
+            quote.0
+        );
+        finish_swap(&mut context, &mut contract);
+        assert_eq!(
+            contract.get_fees().0 .0 + contract.get_protocol_fees().0 .0,
+            quote.0
+        );
+        assert_eq!(contract.get_fee_amount(accounts(3), U128(0)), U128(0));
+    }
+
+    #[test]
+    fn test_referral_fee() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_protocol_fee(2000, accounts(1));
+        contract.set_referral_fee(5000);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(
+            U128(111),
+            Some(U128(0)),
+            u64::MAX,
+            None,
+            None,
+            Some(accounts(5)),
+        );
+        let fee = scheduled_call::<FtDepositArgs>("callback_ft_deposit_balance").fee;
+        finish_swap(&mut context, &mut contract);
+        let protocol_fee = mul_div(fee, 2000, BPS_DENOMINATOR);
+        let referral_fee = mul_div(fee - protocol_fee, 5000, BPS_DENOMINATOR);
+        assert!(referral_fee > 0);
+        assert_eq!(
+            contract.get_referral_fees(accounts(5)),
+            (U128(referral_fee), U128(0))
+        );
+        assert_eq!(contract.get_protocol_fees().0 .0, protocol_fee);
+        assert_eq!(contract.get_fees().0 .0, fee - protocol_fee - referral_fee);
+
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.claim_referral_fees();
+        let args: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(
+            (args.sender_id, args.receiver_id, args.amount),
+            (accounts(0), accounts(5), referral_fee)
+        );
+        assert_eq!(contract.get_referral_fees(accounts(5)), (U128(0), U128(0)));
+        transfers_done(&mut context, vec![true]);
+        contract.callback_claim_referral_fees(accounts(5), referral_fee, 0);
+        assert_eq!(contract.a_referral_fees, 0);
+    }
+
+    #[test]
+    fn test_referral_fee_without_referrer() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_referral_fee(5000);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        let fee = scheduled_call::<FtDepositArgs>("callback_ft_deposit_balance").fee;
+        finish_swap(&mut context, &mut contract);
+        assert_eq!(contract.get_fees().0 .0, fee);
+        assert_eq!(contract.a_referral_fees, 0);
+    }
+
+    #[test]
+    fn test_claim_referral_fees_failed_transfer() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_referral_fee(10_000);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_b(
+            U128(111),
+            Some(U128(0)),
+            u64::MAX,
+            None,
+            None,
+            Some(accounts(5)),
+        );
+        finish_swap(&mut context, &mut contract);
+        let fees = contract.get_referral_fees(accounts(5));
+        assert!(fees.0 .0 == 0 && fees.1 .0 > 0);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.claim_referral_fees();
+        transfers_done(&mut context, vec![false]);
+        contract.callback_claim_referral_fees(accounts(5), 0, fees.1 .0);
+        assert_eq!(contract.get_referral_fees(accounts(5)), fees);
+        assert_eq!(contract.b_referral_fees, fees.1 .0);
+    }
+
+    #[test]
+    #[should_panic(expected = "no referral fees to claim")]
+    fn test_claim_referral_fees_without_fees() {
+        let (_, mut contract) = setup();
+        contract.claim_referral_fees();
+    }
+
+    #[test]
+    #[should_panic(expected = "protocol_fee_bps must not exceed 10000")]
+    fn test_protocol_fee_too_high() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_protocol_fee(10_001, accounts(5));
+    }
+
+    #[test]
+    fn test_fee_cuts_never_exceed_the_fee() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_fee(MAX_FEE_BPS);
+        contract.set_protocol_fee(10_000, accounts(1));
+        contract.set_referral_fee(10_000);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(
+            U128(111),
+            Some(U128(0)),
+            u64::MAX,
+            None,
+            None,
+            Some(accounts(5)),
+        );
+        let fee = scheduled_call::<FtDepositArgs>("callback_ft_deposit_balance").fee;
+        finish_swap(&mut context, &mut contract);
+        // the protocol takes the whole fee, so there is nothing left for the referrer or the providers
+        assert_eq!(contract.get_protocol_fees().0 .0, fee);
+        assert_eq!(contract.get_referral_fees(accounts(5)), (U128(0), U128(0)));
+        assert_eq!(contract.get_fees().0 .0, 0);
+    }
+
+    #[test]
+    fn test_collect_fees() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let a_fees = contract.get_fees().0 .0;
+        // the owner holds all shares, so it earned all but the rounding of the fee per share
+        let owner_fees = contract.get_claimable_fees(accounts(1)).0 .0;
+        assert!(owner_fees <= a_fees && owner_fees + 1 >= a_fees);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.collect_fees(accounts(2));
+        let args: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!((args.receiver_id, args.amount), (accounts(1), owner_fees));
+        assert_eq!(contract.get_fees().0 .0, a_fees - owner_fees);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_collect_fees(
+            accounts(2),
+            accounts(1),
+            owner_fees,
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(contract.get_fees().0 .0, a_fees);
+        assert_eq!(contract.get_claimable_fees(accounts(1)).0 .0, owner_fees);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.collect_fees(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_collect_fees(accounts(2), accounts(1), owner_fees, Ok(()));
+        assert_eq!(contract.get_fees(), (U128(a_fees - owner_fees), U128(0)));
+        assert_eq!(contract.get_claimable_fees(accounts(1)), (U128(0), U128(0)));
+    }
+
+    /// adds liquidity for `account_id` worth `parts` tenths of the reserves
+    fn provide_liquidity(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        account_id: AccountId,
+        parts: u128,
+    ) {
+        register(context, contract, account_id.clone());
+        contract.add_liquidity(U128(A_TICKER / 10 * parts), U128(B_TICKER / 10 * parts));
+        let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_add_liquidity(
+            account_id,
+            args.shares,
+            args.a_amount,
+            args.b_amount,
+            Ok(()),
+            Ok(()),
+        );
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+    }
+
+    #[test]
+    fn test_claim_fees_proportional_to_shares() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        provide_liquidity(&mut context, &mut contract, accounts(4), 1);
+        provide_liquidity(&mut context, &mut contract, accounts(5), 3);
+        let total = contract.total_shares;
+        let small = contract.get_share_balance(accounts(4)).0;
+        let large = contract.get_share_balance(accounts(5)).0;
+        assert!(large > 2 * small);
+
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        contract.deposit_b(U128(5000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let (a_fees, b_fees) = (contract.get_fees().0 .0, contract.get_fees().1 .0);
+        assert!(a_fees > 0 && b_fees > 0);
+        let (a_small, b_small) = contract.get_claimable_fees(accounts(4));
+        let (a_large, b_large) = contract.get_claimable_fees(accounts(5));
+        for (claimable, fees, shares) in [
+            (a_small.0, a_fees, small),
+            (b_small.0, b_fees, small),
+            (a_large.0, a_fees, large),
+            (b_large.0, b_fees, large),
+        ] {
+            let share = mul_div(fees, shares, total);
+            assert!(claimable <= share && claimable + 1 >= share);
+        }
+        assert!(a_large.0 > 2 * a_small.0 && b_large.0 > 2 * b_small.0);
+
+        // the claim leaves the shares and the other provider's fees as they are
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.claim_fees();
+        let args: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!((args.receiver_id, args.amount), (accounts(5), b_large.0));
+        transfers_done(&mut context, vec![true, true]);
+        contract.callback_claim_fees(accounts(5), a_large.0, b_large.0);
+        assert_eq!(contract.get_share_balance(accounts(5)).0, large);
+        assert_eq!(contract.get_claimable_fees(accounts(5)), (U128(0), U128(0)));
+        assert_eq!(contract.get_claimable_fees(accounts(4)), (a_small, b_small));
+        assert_eq!(
+            contract.get_fees(),
+            (U128(a_fees - a_large.0), U128(b_fees - b_large.0))
+        );
+    }
+
+    #[test]
+    fn test_fees_before_providing_are_not_claimable() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        provide_liquidity(&mut context, &mut contract, accounts(4), 1);
+        assert_eq!(contract.get_claimable_fees(accounts(4)), (U128(0), U128(0)));
+
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let earned = contract.get_claimable_fees(accounts(4)).0 .0;
+        assert!(earned > 0);
+        // removing the liquidity settles the fees, which stay claimable
+        let shares = contract.get_share_balance(accounts(4));
+        withdraw_liquidity(&mut context, &mut contract, accounts(4), shares.0);
+        assert_eq!(contract.get_claimable_fees(accounts(4)).0 .0, earned);
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        assert_eq!(contract.get_claimable_fees(accounts(4)).0 .0, earned);
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant call")]
+    fn test_claim_fees_during_swap() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        provide_liquidity(&mut context, &mut contract, accounts(4), 1);
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        // the swap in flight reads the pool's balance before and after pulling its input
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        contract.claim_fees();
+    }
+
+    #[test]
+    fn test_fee_claims_release_the_lock() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        provide_liquidity(&mut context, &mut contract, accounts(4), 1);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_protocol_fee(2500, accounts(5));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+
+        let (a_earned, _) = contract.get_claimable_fees(accounts(4));
+        contract.claim_fees();
+        assert!(contract.locked);
+        transfers_done(&mut context, vec![true]);
+        contract.callback_claim_fees(accounts(4), a_earned.0, 0);
+        assert!(!contract.locked);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let protocol_fees = contract.get_protocol_fees().0 .0;
+        contract.collect_protocol_fees(accounts(2));
+        assert!(contract.locked);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_collect_protocol_fees(accounts(2), protocol_fees, Ok(()));
+        assert!(!contract.locked);
+    }
+
+    #[test]
+    fn test_claim_fees_transfer_failed() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        provide_liquidity(&mut context, &mut contract, accounts(4), 1);
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let (a_earned, b_earned) = contract.get_claimable_fees(accounts(4));
+        let a_fees = contract.get_fees().0;
+        assert!(a_earned.0 > 0 && b_earned.0 == 0);
+
+        contract.claim_fees();
+        assert_eq!(contract.get_claimable_fees(accounts(4)), (U128(0), U128(0)));
+        transfers_done(&mut context, vec![false]);
+        contract.callback_claim_fees(accounts(4), a_earned.0, 0);
+        assert_eq!(
+            contract.get_claimable_fees(accounts(4)),
+            (a_earned, b_earned)
+        );
+        assert_eq!(contract.get_fees().0, a_fees);
+    }
+
+    #[test]
+    #[should_panic(expected = "no fees to claim")]
+    fn test_claim_fees_empty() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        provide_liquidity(&mut context, &mut contract, accounts(4), 1);
+        contract.claim_fees();
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_collect_fees_without_yocto() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.collect_fees(accounts(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "no fees to collect")]
+    fn test_collect_fees_empty() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.collect_fees(accounts(3));
+    }
+
+    #[test]
+    fn test_owner_holds_initial_shares() {
+        let (_, contract) = setup();
+        let initial = (U256::from(A_TICKER) * U256::from(B_TICKER))
+            .integer_sqrt()
+            .as_u128();
+        assert_eq!(contract.total_shares, initial);
+        assert_eq!(contract.shares.get(&accounts(1)), Some(initial));
+    }
+
+    #[test]
+    fn test_add_liquidity_proportional() {
+        let (mut context, mut contract) = setup();
+        register(&mut context, &mut contract, accounts(4));
+        let total = contract.total_shares;
+        // offering more B than the A side needs only pulls the proportional B
+        contract.add_liquidity(U128(A_TICKER / 10), U128(B_TICKER));
+        let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+        assert_eq!(args.shares, total / 10);
+        assert_eq!(args.a_amount, mul_div_ceil(total / 10, A_TICKER, total));
+        assert_eq!(args.b_amount, mul_div_ceil(total / 10, B_TICKER, total));
+        assert!(args.a_amount <= A_TICKER / 10 && args.b_amount <= B_TICKER / 10 + 1);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_add_liquidity(
+            accounts(4),
+            args.shares,
+            args.a_amount,
+            args.b_amount,
+            Ok(()),
+            Ok(()),
+        );
+        assert_eq!(contract.shares.get(&accounts(4)), Some(total / 10));
+        assert_eq!(contract.total_shares, total + total / 10);
+        assert_eq!(contract.get_info().0 .2, A_TICKER + args.a_amount);
+        assert_eq!(contract.get_info().1 .2, B_TICKER + args.b_amount);
+    }
+
+    #[test]
+    fn test_quote_add_liquidity() {
+        let (mut context, mut contract) = setup();
+        register(&mut context, &mut contract, accounts(4));
+        let quote = contract.quote_add_liquidity(U128(A_TICKER / 7), U128(B_TICKER / 3));
+        contract.add_liquidity(U128(A_TICKER / 7), U128(B_TICKER / 3));
+        let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+        assert_eq!(quote.0, args.shares);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_add_liquidity(
+            accounts(4),
+            args.shares,
+            args.a_amount,
+            args.b_amount,
+            Ok(()),
+            Ok(()),
+        );
+        assert_eq!(contract.get_share_balance(accounts(4)), quote);
+    }
+
+    #[test]
+    fn test_quote_first_add_liquidity() {
+        let (mut context, mut contract) = setup();
+        let total = contract.total_shares;
+        withdraw_liquidity(&mut context, &mut contract, accounts(1), total);
+        assert_eq!(
+            contract.quote_add_liquidity(U128(1000), U128(1000)),
+            U128(0)
+        );
+
+        register(&mut context, &mut contract, accounts(4));
+        let quote = contract.quote_add_liquidity(U128(4_000_000), U128(9_000_000));
+        contract.add_liquidity(U128(4_000_000), U128(9_000_000));
+        let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_add_liquidity(
+            accounts(4),
+            args.shares,
+            args.a_amount,
+            args.b_amount,
+            Ok(()),
+            Ok(()),
+        );
+        assert_eq!(quote, U128(6_000_000 - MINIMUM_LIQUIDITY));
+        assert_eq!(contract.get_share_balance(accounts(4)), quote);
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct ZapInArgs {
+        amount_in: Balance,
+        min_shares: Balance,
+    }
+
+    #[test]
+    fn test_zap_in_matches_swap_and_add() {
+        let a_in = 1000 * 10_u128.pow(18);
+        // swapping half and adding the rest by hand
+        let (mut context, mut contract) = setup_with_fee(30);
+        register(&mut context, &mut contract, accounts(4));
+        contract.deposit_a(U128(500), Some(U128(0)), u64::MAX, None, None, None);
+        let b_out = finish_swap(&mut context, &mut contract);
+        contract.add_liquidity(U128(a_in / 2), U128(b_out));
+        let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+        let manual_shares = args.shares;
+
+        let (mut context, mut contract) = setup_with_fee(30);
+        register(&mut context, &mut contract, accounts(4));
+        let total = contract.total_shares;
+        contract.zap_in(accounts(2), U128(a_in), None);
+        let transfer: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(
+            (transfer.sender_id, transfer.receiver_id, transfer.amount),
+            (accounts(4), accounts(0), a_in)
+        );
+        let args: ZapInArgs = scheduled_call("callback_zap_in");
+        assert_eq!((args.amount_in, args.min_shares), (a_in, 0));
+        // what is left of the input and the output are in the proportion of the reserves after the swap
+        let (swap_in, fee, b_out, quote) = contract.calc_zap(&accounts(2), a_in);
+        let by_a = mul_div(total, a_in - swap_in, A_TICKER + swap_in - fee);
+        let by_b = mul_div(total, b_out, B_TICKER - b_out);
+        assert!(by_a.abs_diff(by_b) <= by_b / 1_000_000);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let shares = contract
+            .callback_zap_in(accounts(4), accounts(2), a_in, 0, Ok(()))
+            .0;
+        assert!(!contract.locked);
+        assert_eq!(shares, quote);
+        assert_eq!(contract.get_share_balance(accounts(4)).0, shares);
+        assert_eq!(contract.total_shares, total + shares);
+        // by hand the A left over after the B side is matched stays with the provider, the zap uses it all
+        assert!(shares >= manual_shares);
+        assert!(shares - manual_shares <= manual_shares / 100);
+        let a_fees = contract.get_fees().0 .0;
+        assert!(a_fees > 0);
+        assert_eq!(contract.get_info().0 .2, A_TICKER + a_in - a_fees);
+        assert_eq!(contract.get_info().1 .2, B_TICKER);
+    }
+
+    #[test]
+    fn test_zap_in_refunds_short_shares() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        register(&mut context, &mut contract, accounts(5));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let b_in = 100 * 10_u128.pow(15);
+        assert_eq!(
+            contract.callback_zap_in(accounts(5), accounts(3), b_in, u128::MAX, Ok(())),
+            U128(0)
+        );
+        let transfer: TransferFromArgs = scheduled_call("transfer_from");
+        assert_eq!(
+            (transfer.sender_id, transfer.receiver_id, transfer.amount),
+            (accounts(0), accounts(5), b_in)
+        );
+        assert_eq!(contract.get_share_balance(accounts(5)), U128(0));
+        assert_eq!(contract.get_info().1 .2, B_TICKER);
+        assert_eq!(contract.get_fees(), (U128(0), U128(0)));
+    }
+
+    #[test]
+    fn test_zap_in_transfer_failed() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        register(&mut context, &mut contract, accounts(4));
+        contract.zap_in(accounts(3), U128(10_u128.pow(15)), None);
+        assert!(contract.locked);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let result = contract.callback_zap_in(
+            accounts(4),
+            accounts(3),
+            10_u128.pow(15),
+            0,
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(result, U128(0));
+        assert!(!contract.locked);
+        assert_eq!(contract.get_info().1 .2, B_TICKER);
+    }
+
+    #[test]
+    #[should_panic(expected = "slippage exceeded")]
+    fn test_zap_in_below_min_shares() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        register(&mut context, &mut contract, accounts(4));
+        contract.zap_in(accounts(2), U128(10_u128.pow(18)), Some(U128(u128::MAX)));
+    }
+
+    #[test]
+    #[should_panic(expected = "account is not registered, call storage_deposit first")]
+    fn test_zap_in_unregistered() {
+        let (_, mut contract) = setup();
+        contract.zap_in(accounts(2), U128(10_u128.pow(18)), None);
+    }
+
+    #[test]
+    fn test_share_views() {
+        let (mut context, mut contract) = setup();
+        let total = contract.get_total_shares().0;
+        for (account_id, a_amount, b_amount) in [
+            (accounts(4), A_TICKER / 10, B_TICKER / 10),
+            (accounts(5), A_TICKER / 5, B_TICKER / 5),
+        ] {
+            register(&mut context, &mut contract, account_id.clone());
+            contract.add_liquidity(U128(a_amount), U128(b_amount));
+            let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+            testing_env!(context.predecessor_account_id(accounts(0)).build());
+            contract.callback_add_liquidity(
+                account_id,
+                args.shares,
+                args.a_amount,
+                args.b_amount,
+                Ok(()),
+                Ok(()),
+            );
+        }
+        assert_eq!(contract.get_share_balance(accounts(4)).0, total / 10);
+        assert_eq!(contract.get_share_balance(accounts(5)).0, total / 5);
+        assert_eq!(contract.get_share_balance(accounts(3)).0, 0);
+        assert_eq!(
+            contract.get_total_shares().0,
+            total + total / 10 + total / 5
+        );
+
+        let (a_value, b_value) = contract.get_share_value(accounts(5));
+        let (a_reserve, b_reserve) = (contract.get_info().0 .2, contract.get_info().1 .2);
+        assert_eq!(
+            (a_value.0, b_value.0),
+            (
+                mul_div(total / 5, a_reserve, contract.get_total_shares().0),
+                mul_div(total / 5, b_reserve, contract.get_total_shares().0)
+            )
+        );
+        // the second provider put in twice as much as the first one
+        let (a_half, b_half) = contract.get_share_value(accounts(4));
+        assert!(a_value.0.abs_diff(a_half.0 * 2) <= 1 && b_value.0.abs_diff(b_half.0 * 2) <= 1);
+        assert_eq!(contract.get_share_value(accounts(3)), (U128(0), U128(0)));
+    }
+
+    #[test]
+    fn test_storage_deposit() {
+        let (mut context, mut contract) = setup();
+        let min = contract.storage_balance_bounds().min.0;
+        assert!(min > 0);
+        assert!(contract.storage_balance_of(accounts(4)).is_none());
+        register(&mut context, &mut contract, accounts(4));
+        let balance = contract.storage_balance_of(accounts(4)).unwrap();
+        assert_eq!((balance.total.0, balance.available.0), (min, 0));
+        assert_eq!(contract.get_share_balance(accounts(4)).0, 0);
+        // the initial liquidity registers the owner
+        assert!(contract.storage_balance_of(accounts(1)).is_some());
+    }
+
+    #[test]
+    fn test_storage_deposit_twice() {
+        let (mut context, mut contract) = setup();
+        register(&mut context, &mut contract, accounts(4));
+        let total_shares = contract.get_total_shares();
+        // a second registration refunds the whole deposit and keeps the entry as it is
+        testing_env!(context.attached_deposit(10).build());
+        contract.storage_deposit(None, None);
+        let refunds: Vec<AccountId> = get_created_receipts()
+            .into_iter()
+            .filter(|receipt| {
+                receipt
+                    .actions
+                    .iter()
+                    .any(|action| matches!(action, VmAction::Transfer { .. }))
+            })
+            .map(|receipt| receipt.receiver_id)
+            .collect();
+        assert_eq!(refunds, vec![accounts(4)]);
+        assert_eq!(contract.get_share_balance(accounts(4)).0, 0);
+        assert_eq!(contract.get_total_shares(), total_shares);
+    }
+
+    #[test]
+    #[should_panic(expected = "The attached deposit is less than the minimum storage balance")]
+    fn test_storage_deposit_too_small() {
+        let (mut context, mut contract) = setup();
+        let deposit = contract.storage_balance_bounds().min.0 - 1;
+        testing_env!(context.attached_deposit(deposit).build());
+        contract.storage_deposit(None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "account is not registered, call storage_deposit first")]
+    fn test_add_liquidity_unregistered() {
+        let (_, mut contract) = setup();
+        contract.add_liquidity(U128(10_u128.pow(18)), U128(10_u128.pow(15)));
+    }
+
+    #[test]
+    fn test_storage_unregister() {
+        let (mut context, mut contract) = setup();
+        register(&mut context, &mut contract, accounts(4));
+        testing_env!(context.attached_deposit(1).build());
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(accounts(4)).is_none());
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    fn test_is_registered() {
+        let (mut context, mut contract) = setup();
+        assert!(!contract.is_registered(accounts(4)));
+        register(&mut context, &mut contract, accounts(4));
+        assert!(contract.is_registered(accounts(4)));
+        // the owner is registered with the initial shares
+        assert!(contract.is_registered(accounts(1)));
+        testing_env!(context.attached_deposit(1).build());
+        contract.storage_unregister(None);
+        assert!(!contract.is_registered(accounts(4)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't unregister the account with positive shares without force")]
+    fn test_storage_unregister_with_shares() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Can't unregister the account with unclaimed fees without force, call claim_fees first"
+    )]
+    fn test_storage_unregister_with_unclaimed_fees() {
+        let (mut context, mut contract) = setup_with_fee(30);
+        provide_liquidity(&mut context, &mut contract, accounts(4), 1);
+        contract.deposit_a(U128(1000), Some(U128(0)), u64::MAX, None, None, None);
+        finish_swap(&mut context, &mut contract);
+        let shares = contract.get_share_balance(accounts(4));
+        withdraw_liquidity(&mut context, &mut contract, accounts(4), shares.0);
+        testing_env!(context.attached_deposit(1).build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Can't burn the last shares of a pool with reserves, call remove_liquidity first"
+    )]
+    fn test_storage_unregister_sole_provider_forced() {
+        let (mut context, mut contract) = setup();
+        // the owner holds every share of a pool built by new
+        assert_eq!(
+            contract.get_share_balance(accounts(1)).0,
+            contract.total_shares
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.storage_unregister(Some(true));
+    }
+
+    #[test]
+    fn test_storage_unregister_forced_with_other_providers() {
+        let (mut context, mut contract) = setup();
+        provide_liquidity(&mut context, &mut contract, accounts(4), 1);
+        let total = contract.total_shares;
+        let owner_shares = contract.get_share_balance(accounts(1)).0;
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        assert!(contract.storage_unregister(Some(true)));
+        assert_eq!(contract.total_shares, total - owner_shares);
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant call")]
+    fn test_storage_unregister_during_swap() {
+        let (mut context, mut contract) = setup();
+        register(&mut context, &mut contract, accounts(4));
+        contract.deposit_a(U128(111), Some(U128(0)), u64::MAX, None, None, None);
+        testing_env!(context.attached_deposit(1).build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_add_liquidity_partially_failed() {
+        let (mut context, mut contract) = setup();
+        let total = contract.total_shares;
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_add_liquidity(accounts(4), 10, 10, 10, Ok(()), Err(PromiseError::Failed));
+        assert_eq!(contract.shares.get(&accounts(4)), None);
+        assert_eq!(contract.total_shares, total);
+        assert_eq!(contract.get_info().0 .2, A_TICKER);
+    }
+
+    #[test]
+    fn test_remove_all_liquidity_then_first_deposit() {
+        let (mut context, mut contract) = setup();
+        let total = contract.total_shares;
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.remove_liquidity(U128(total));
+        let args: RemoveLiquidityArgs = scheduled_call("callback_remove_liquidity");
+        assert_eq!((args.a_amount, args.b_amount), (A_TICKER, B_TICKER));
+        assert_eq!(contract.total_shares, 0);
+        assert_eq!(contract.shares.get(&accounts(1)), Some(0));
+        assert_eq!((contract.get_info().0 .2, contract.get_info().1 .2), (0, 0));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_remove_liquidity(
+            accounts(1),
+            args.a_amount,
+            args.b_amount,
+            Ok(()),
+            Ok(()),
+        );
+
+        // the next deposit into the empty pool mints sqrt(a * b)
+        register(&mut context, &mut contract, accounts(4));
+        contract.add_liquidity(U128(4_000_000), U128(9_000_000));
+        let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+        assert_eq!(args.shares, 6_000_000 - MINIMUM_LIQUIDITY);
+        assert_eq!((args.a_amount, args.b_amount), (4_000_000, 9_000_000));
+    }
+
+    #[test]
+    fn test_minimum_liquidity_defeats_share_inflation() {
+        let (mut context, mut contract) = setup();
+        let total = contract.total_shares;
+        withdraw_liquidity(&mut context, &mut contract, accounts(1), total);
+
+        // the attacker is the first provider with a tiny deposit and donates a lot to the reserves
+        let donation = 10_u128.pow(24);
+        register(&mut context, &mut contract, accounts(4));
+        contract.add_liquidity(U128(2000), U128(2000));
+        let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+        assert_eq!(args.shares, 2000 - MINIMUM_LIQUIDITY);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_add_liquidity(accounts(4), args.shares, 2000, 2000, Ok(()), Ok(()));
+        assert_eq!(contract.total_shares, 2000);
+        contract.callback_update_tickers(2000 + donation, 2000 + donation, 0, Ok(()));
+
+        // the victim still gets shares worth what they put in
+        register(&mut context, &mut contract, accounts(5));
+        contract.add_liquidity(U128(donation), U128(donation));
+        let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_add_liquidity(
+            accounts(5),
+            args.shares,
+            args.a_amount,
+            args.b_amount,
+            Ok(()),
+            Ok(()),
+        );
+        let victim_value = contract.get_share_value(accounts(5)).0 .0;
+        assert!(victim_value >= args.a_amount / 100 * 99);
+        // and the attacker loses about half of the donation to the locked shares
+        let attacker_value = contract.get_share_value(accounts(4)).0 .0;
+        assert!(attacker_value < (2000 + donation) / 100 * 51);
+    }
+
+    #[test]
+    #[should_panic(expected = "the first deposit must mint more than MINIMUM_LIQUIDITY shares")]
+    fn test_first_deposit_below_minimum_liquidity() {
+        let (mut context, mut contract) = setup();
+        let total = contract.total_shares;
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.remove_liquidity(U128(total));
+        contract.add_liquidity(U128(1000), U128(1000));
+    }
+
+    #[test]
+    #[should_panic(expected = "liquidity amount is too small")]
+    fn test_add_liquidity_too_small() {
+        let (mut context, mut contract) = setup();
+        register(&mut context, &mut contract, accounts(4));
+        contract.add_liquidity(U128(1), U128(1));
+    }
+
+    #[test]
+    fn test_quote_remove_liquidity() {
+        let (mut context, mut contract) = setup();
+        let shares = contract.total_shares / 3;
+        let quote = contract.quote_remove_liquidity(U128(shares));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.remove_liquidity(U128(shares));
+        let args: RemoveLiquidityArgs = scheduled_call("callback_remove_liquidity");
+        assert_eq!(quote, (U128(args.a_amount), U128(args.b_amount)));
+    }
+
+    #[test]
+    #[should_panic(expected = "not enough shares")]
+    fn test_quote_remove_liquidity_over_total() {
+        let (_, contract) = setup();
+        contract.quote_remove_liquidity(U128(contract.total_shares + 1));
+    }
+
+    #[test]
+    fn test_remove_liquidity_failed_transfer() {
+        let (mut context, mut contract) = setup();
+        let total = contract.total_shares;
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.remove_liquidity(U128(total / 2));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_remove_liquidity(
+            accounts(1),
+            A_TICKER / 2,
+            B_TICKER / 2,
+            Ok(()),
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(contract.get_info().0 .2, A_TICKER - A_TICKER / 2);
+        assert_eq!(contract.get_info().1 .2, B_TICKER);
+    }
+
+    #[test]
+    fn test_liquidity_changes_hold_the_lock() {
+        let (mut context, mut contract) = setup();
+        register(&mut context, &mut contract, accounts(4));
+        contract.add_liquidity(U128(A_TICKER / 10), U128(B_TICKER / 10));
+        assert!(contract.locked);
+        let args: AddLiquidityArgs = scheduled_call("callback_add_liquidity");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_add_liquidity(
+            accounts(4),
+            args.shares,
+            args.a_amount,
+            args.b_amount,
+            Ok(()),
+            Err(PromiseError::Failed),
+        );
+        assert!(!contract.locked);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.remove_liquidity(U128(1000));
+        assert!(contract.locked);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.callback_remove_liquidity(accounts(1), 0, 0, Ok(()), Ok(()));
+        assert!(!contract.locked);
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant call")]
+    fn test_remove_liquidity_during_swap() {
+        let (mut context, mut contract) = setup();
+        // the swap callback sets the tickers it priced against, so they may not move before it runs
+        contract.deposit_a(U128(111), None, u64::MAX, None, None, None);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.remove_liquidity(U128(contract.total_shares / 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant call")]
+    fn test_add_liquidity_during_swap() {
+        let (mut context, mut contract) = setup();
+        register(&mut context, &mut contract, accounts(4));
+        contract.deposit_a(U128(111), None, u64::MAX, None, None, None);
+        contract.add_liquidity(U128(A_TICKER / 10), U128(B_TICKER / 10));
+    }
+
+    #[test]
+    #[should_panic(expected = "not enough shares")]
+    fn test_remove_liquidity_without_shares() {
+        let (_, mut contract) = setup();
+        contract.remove_liquidity(U128(1));
     }
 }
@@ -0,0 +1,117 @@
+//! State migration for pools deployed before the fees, shares and pause settings existed.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, LookupSet, Vector};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+
+use crate::u256::U256;
+use crate::{Contract, ContractExt, StorageKey, DEFAULT_SLIPPAGE_BPS, GAS_FOR_CALLBACK, VERSION};
+
+/// The state layout of the first release, in this order:
+/// `owner_id`, `ratio`, `a_ticker`, `a_contract_id`, `a_contract_name`, `a_contract_decimals`,
+/// `b_ticker`, `b_contract_id`, `b_contract_name`, `b_contract_decimals`.
+/// The decimals were a placeholder 1 until the token metadata arrived.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct OldContract {
+    pub(crate) owner_id: AccountId,
+    pub(crate) ratio: u128,
+    pub(crate) a_ticker: Balance,
+    pub(crate) a_contract_id: AccountId,
+    pub(crate) a_contract_name: String,
+    pub(crate) a_contract_decimals: u8,
+    pub(crate) b_ticker: Balance,
+    pub(crate) b_contract_id: AccountId,
+    pub(crate) b_contract_name: String,
+    pub(crate) b_contract_decimals: u8,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Reads the state of the first release and carries the owner, tokens, metadata and reserves over.
+    /// The old pools charged no fee, so the fee starts at 0, and the owner holds all shares of the
+    /// reserves as after `new`. The registrations are taken as done, since the old pool traded.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: OldContract = env::state_read().expect("failed to read the old state");
+        // the metadata of a token has arrived once its name is set
+        let a_contract_decimals =
+            Some(old.a_contract_decimals).filter(|_| !old.a_contract_name.is_empty());
+        let b_contract_decimals =
+            Some(old.b_contract_decimals).filter(|_| !old.b_contract_name.is_empty());
+        let mut this = Self {
+            owner_id: old.owner_id.clone(),
+            pending_owner: None,
+            pending_owner_expiry: 0,
+            ratio: U256::from(old.ratio),
+            ratio_precision: 0,
+            k: U256::from(old.a_ticker) * U256::from(old.b_ticker),
+            a_ticker: old.a_ticker,
+            a_contract_id: old.a_contract_id,
+            a_contract_name: old.a_contract_name,
+            a_contract_decimals,
+            b_ticker: old.b_ticker,
+            b_contract_id: old.b_contract_id,
+            b_contract_name: old.b_contract_name,
+            b_contract_decimals,
+            metadata_loaded: a_contract_decimals.is_some() && b_contract_decimals.is_some(),
+            registered_a: true,
+            registered_b: true,
+            fee_bps: 0,
+            fee_start_bps: 0,
+            fee_end_bps: 0,
+            fee_ramp_start: 0,
+            fee_ramp_end: 0,
+            a_fees: 0,
+            b_fees: 0,
+            a_fee_per_share: U256::zero(),
+            b_fee_per_share: U256::zero(),
+            reward_debts: LookupMap::new(StorageKey::RewardDebts),
+            protocol_fee_bps: 0,
+            fee_recipient: old.owner_id.clone(),
+            a_protocol_fees: 0,
+            b_protocol_fees: 0,
+            referral_bps: 0,
+            referral_fees: LookupMap::new(StorageKey::ReferralFees),
+            a_referral_fees: 0,
+            b_referral_fees: 0,
+            shares: LookupMap::new(StorageKey::Shares),
+            total_shares: 0,
+            account_storage_usage: 0,
+            paused: false,
+            paused_a_to_b: false,
+            paused_b_to_a: false,
+            max_trade_a: None,
+            max_trade_b: None,
+            min_reserve_a: 0,
+            min_reserve_b: 0,
+            breaker_bps: 0,
+            locked: false,
+            locked_at: 0,
+            price_cumulative_a: 0,
+            price_cumulative_b: 0,
+            last_price_timestamp: env::block_timestamp(),
+            last_update_block: env::block_height(),
+            flash_receivers: LookupSet::new(StorageKey::FlashReceivers),
+            trades: Vector::new(StorageKey::Trades),
+            trade_head: 0,
+            allowlist: None,
+            volume_a_in: 0,
+            volume_b_in: 0,
+            wrap_contract_id: None,
+            oracle_account: None,
+            oracle_tolerance_bps: 0,
+            default_slippage_bps: DEFAULT_SLIPPAGE_BPS,
+            // the first release didn't record its creation
+            created_at: env::block_timestamp(),
+            callback_gas: GAS_FOR_CALLBACK,
+        };
+        let initial_shares = this.k.integer_sqrt().as_u128();
+        this.measure_account_storage_usage();
+        this.shares.insert(&old.owner_id, &initial_shares);
+        this.total_shares = initial_shares;
+        this.calc_ratio();
+        log!("Migrated the state to version {}", VERSION);
+        this
+    }
+}
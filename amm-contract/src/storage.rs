@@ -0,0 +1,133 @@
+//! Storage management (nep-145) for liquidity providers:
+//! <https://github.com/near/NEPs/blob/master/neps/nep-0145.md>
+//!
+//! An account is registered while it has an entry in the shares map, so it has to pay for that
+//! entry with `storage_deposit` before its first `add_liquidity`.
+
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, require, AccountId, Balance, Promise};
+
+use crate::{Contract, ContractExt};
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    // every registration costs the same, so `registration_only` changes nothing
+    #[allow(unused_variables)]
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount: Balance = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        if self.shares.contains_key(&account_id) {
+            log!("The account is already registered, refunding the deposit");
+            if amount > 0 {
+                Promise::new(env::predecessor_account_id()).transfer(amount);
+            }
+        } else {
+            let min_balance = self.storage_balance_bounds().min.0;
+            require!(
+                amount >= min_balance,
+                "The attached deposit is less than the minimum storage balance"
+            );
+            self.shares.insert(&account_id, &0);
+            let refund = amount - min_balance;
+            if refund > 0 {
+                Promise::new(env::predecessor_account_id()).transfer(refund);
+            }
+        }
+        self.internal_storage_balance_of(&account_id).unwrap()
+    }
+
+    /// The minimum and maximum storage balance are the same, so there is never anything available:
+    /// this panics if `amount > 0` and otherwise only returns the storage balance.
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let storage_balance = self.internal_storage_balance_of(&account_id);
+        require!(storage_balance.is_some(), "The account is not registered");
+        require!(
+            amount.unwrap_or(U128(0)).0 == 0,
+            "The amount is greater than the available storage balance"
+        );
+        storage_balance.unwrap()
+    }
+
+    /// Closes the account of the caller and refunds its storage deposit.
+    /// Remaining shares or unclaimed fees block this unless `force` is set, which burns the shares in
+    /// favour of the other providers and leaves the fees unclaimable in the pool. The last provider
+    /// can't burn theirs while the pool holds reserves, the next deposit would mint shares of all of them.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        // a liquidity or fee callback in flight settles against the current shares
+        require!(!self.locked, "reentrant call");
+        let account_id = env::predecessor_account_id();
+        let shares = match self.shares.get(&account_id) {
+            Some(shares) => shares,
+            None => {
+                log!("The account {} is not registered", account_id);
+                return false;
+            }
+        };
+        let force = force.unwrap_or(false);
+        require!(
+            shares == 0 || force,
+            "Can't unregister the account with positive shares without force"
+        );
+        require!(
+            shares == 0 || shares < self.total_shares || (self.a_ticker == 0 && self.b_ticker == 0),
+            "Can't burn the last shares of a pool with reserves, call remove_liquidity first"
+        );
+        let fees = self.settled_fees(&account_id);
+        require!(
+            (fees.a_pending == 0 && fees.b_pending == 0) || force,
+            "Can't unregister the account with unclaimed fees without force, call claim_fees first"
+        );
+        self.shares.remove(&account_id);
+        self.reward_debts.remove(&account_id);
+        self.total_shares -= shares;
+        Promise::new(account_id).transfer(self.storage_balance_bounds().min.0 + 1);
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        let required_storage_balance =
+            Balance::from(self.account_storage_usage) * env::storage_byte_cost();
+        StorageBalanceBounds {
+            min: required_storage_balance.into(),
+            max: Some(required_storage_balance.into()),
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.internal_storage_balance_of(&account_id)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Whether `account_id` has paid its storage with `storage_deposit`, which `add_liquidity` requires.
+    pub fn is_registered(&self, account_id: AccountId) -> bool {
+        self.shares.contains_key(&account_id)
+    }
+}
+
+impl Contract {
+    fn internal_storage_balance_of(&self, account_id: &AccountId) -> Option<StorageBalance> {
+        if self.shares.contains_key(account_id) {
+            Some(StorageBalance {
+                total: self.storage_balance_bounds().min,
+                available: 0.into(),
+            })
+        } else {
+            None
+        }
+    }
+}